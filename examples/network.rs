@@ -37,7 +37,7 @@ fn sync_network(args: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
     let response = reqwest::blocking::get(manifest_url)?;
     let data = response.bytes()?;
 
-    let manifest: RemoteManifest = bincode::deserialize(&data)?;
+    let manifest = RemoteManifest::from_bytes(&data)?;
     let provider = binsync::RemoteChunkProvider::new(url.as_str(), &manifest)?;
 
     let mut syncer = Syncer::new(to_path, provider, manifest.source);
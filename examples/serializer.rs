@@ -26,16 +26,22 @@ fn main() {
         process::exit(1);
     }
 
-    let manifest = Manifest::from_path(from);
+    let manifest = match Manifest::from_path(from) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("Could not generate manifest {}", e);
+            process::exit(1);
+        }
+    };
     let manifest = RemoteManifest::from_manifest(manifest);
-    let manifest_data = bincode::serialize(&manifest).unwrap();
+    let manifest_data = manifest.to_compressed_bytes().unwrap();
 
-    if let Err(_) = fs::create_dir("out") {
+    if fs::create_dir("out").is_err() {
         println!("Could not create ./out does it already exist?");
         process::exit(1);
     }
 
-    if let Err(_) = fs::write("out/manifest.binsync", manifest_data) {
+    if fs::write("out/manifest.binsync", manifest_data).is_err() {
         println!("Could not write manifest file.");
         process::exit(1);
     }
@@ -57,17 +63,18 @@ fn main() {
         }
     }
 
-    for pack in manifest.packs {
-        let file_name = format!("out/{}.binpack", pack.hash);
+    for pack in &manifest.packs {
+        let file_name = format!("out/{}", manifest.pack_file_name(pack));
 
         let mut file = OpenOptions::new()
             .write(true)
             .create(true)
+            .truncate(true)
             .open(file_name)
             .unwrap();
 
-        for chunk_id in pack.chunks {
-            file.write_all(chunks.get_mut(&chunk_id).unwrap()).unwrap();
+        for chunk_id in &pack.chunks {
+            file.write_all(chunks.get_mut(chunk_id).unwrap()).unwrap();
         }
     }
 
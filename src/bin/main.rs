@@ -1,5 +1,7 @@
 use std::{
-    path::Path,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
     process,
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -9,9 +11,13 @@ use std::{
     time::{Duration, Instant},
 };
 
-use binsync::{generate_manifest, CachingChunkProvider, Syncer};
-use clap::{Parser, Subcommand};
+use binsync::{
+    generate_manifest_with_options, signing_key_from_hex, BinsyncError, CachingChunkProvider,
+    Manifest, ManifestOptions, ManifestStats, SigningKey, Syncer,
+};
+use clap::{ArgEnum, Args, Parser, Subcommand};
 use indicatif::{ProgressBar, ProgressStyle};
+use rand::rngs::OsRng;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -23,8 +29,90 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    Sync { from: String, to: String },
-    Generate { from: String },
+    Sync {
+        from: String,
+        to: String,
+        #[clap(flatten)]
+        filter: Filter,
+    },
+    Generate {
+        from: String,
+        #[clap(flatten)]
+        filter: Filter,
+        /// How to write the manifest.
+        #[clap(long, arg_enum, default_value = "debug")]
+        format: Format,
+        /// Writes the manifest to a file instead of printing it.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Creates an Ed25519 key pair for signing manifests.
+    Keygen {
+        /// File to write the secret key to. The public key is printed.
+        output: PathBuf,
+    },
+    /// Signs a binary manifest so clients can check where it came from.
+    Sign {
+        manifest: PathBuf,
+        /// File holding the secret key written by keygen.
+        #[clap(long)]
+        key: PathBuf,
+        /// Writes the signed manifest here instead of replacing the input.
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Shows file, chunk and deduplication numbers for a directory or a
+    /// manifest file.
+    Stats {
+        from: PathBuf,
+        #[clap(flatten)]
+        filter: Filter,
+    },
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+enum Format {
+    Debug,
+    Binary,
+    Compressed,
+    Json,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Format {
+    fn encode(self, manifest: &Manifest) -> Result<Vec<u8>, BinsyncError> {
+        match self {
+            Format::Debug => Ok(format!("{:?}\n", manifest).into_bytes()),
+            Format::Binary => manifest.to_bytes(),
+            Format::Compressed => manifest.to_compressed_bytes(),
+            Format::Json => Ok(manifest.to_json()?.into_bytes()),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => manifest.to_cbor(),
+        }
+    }
+}
+
+// Glob patterns that select which files end up in the manifest.
+#[derive(Args)]
+struct Filter {
+    /// Only include paths matching this pattern. Can be repeated.
+    #[clap(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Exclude paths matching this pattern. Can be repeated.
+    #[clap(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+}
+
+impl Filter {
+    fn options(&self) -> ManifestOptions {
+        ManifestOptions {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            ..Default::default()
+        }
+    }
 }
 
 /// A command-line interface for the crate. Allows you to run various commands
@@ -33,7 +121,7 @@ fn main() {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::Sync { from, to } => {
+        Commands::Sync { from, to, filter } => {
             let now = Instant::now();
 
             println!("[1/2] Generating manifest from {}", from);
@@ -61,7 +149,7 @@ fn main() {
                 }
             });
 
-            let manifest = match generate_manifest(&from) {
+            let manifest = match generate_manifest_with_options(from, &filter.options()) {
                 Ok(manifest) => manifest,
                 Err(err) => {
                     eprintln!("Failed to generate manifest: {}", err);
@@ -100,10 +188,16 @@ fn main() {
 
             println!("Sync completed in {}s", now.elapsed().as_secs());
         }
-        Commands::Generate { from } => {
+        Commands::Generate {
+            from,
+            filter,
+            format,
+            output,
+        } => {
             let now = Instant::now();
 
-            println!("[1/1] Generating manifest from {}", from);
+            // Status goes to stderr so a manifest printed to stdout stays intact.
+            eprintln!("[1/1] Generating manifest from {}", from);
 
             let stop_spinner = Arc::new(AtomicBool::new(false));
             let handle = thread::spawn({
@@ -128,18 +222,168 @@ fn main() {
                 }
             });
 
-            match generate_manifest(&from) {
-                Ok(manifest) => println!("Generated manifest: {:?}", manifest),
-                Err(msg) => {
-                    eprintln!("Error running sync: {}", msg);
-                    process::exit(1);
-                }
+            let result = generate_manifest_with_options(from, &filter.options())
+                .and_then(|manifest| format.encode(&manifest))
+                .and_then(|data| match output {
+                    Some(output) => Ok(fs::write(output, data)?),
+                    None => Ok(io::stdout().write_all(&data)?),
+                });
+
+            if let Err(msg) = result {
+                eprintln!("Error generating manifest: {}", msg);
+                process::exit(1);
             }
 
             stop_spinner.store(true, Ordering::SeqCst);
             handle.join().unwrap();
 
-            println!("Manifest generated in {}s", now.elapsed().as_secs());
+            eprintln!("Manifest generated in {}s", now.elapsed().as_secs());
+        }
+        Commands::Keygen { output } => {
+            let key = SigningKey::generate(&mut OsRng);
+
+            if let Err(err) = write_secret(output, &hex(&key.to_bytes())) {
+                eprintln!("Error writing key: {}", err);
+                process::exit(1);
+            }
+
+            println!("Public key: {}", hex(key.verifying_key().as_bytes()));
+        }
+        Commands::Sign {
+            manifest,
+            key,
+            output,
+        } => {
+            if let Err(err) = sign(manifest, key, output.as_ref().unwrap_or(manifest)) {
+                eprintln!("Error signing manifest: {}", err);
+                process::exit(1);
+            }
+
+            println!("Signed {}", manifest.display());
         }
+        Commands::Stats { from, filter } => {
+            let manifest = if from.is_dir() {
+                Manifest::from_path_with_options(from, &filter.options())
+            } else {
+                read_manifest(from)
+            };
+
+            match manifest {
+                Ok(manifest) => print_stats(&manifest.stats()),
+                Err(err) => {
+                    eprintln!("Error reading manifest: {}", err);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+/// Reads a manifest file written by generate in any format.
+fn read_manifest(path: &Path) -> Result<Manifest, BinsyncError> {
+    let data = fs::read(path)?;
+
+    if data.first() == Some(&b'{') {
+        return Manifest::from_json(&String::from_utf8_lossy(&data));
+    }
+
+    #[cfg(feature = "cbor")]
+    if let Ok(manifest) = Manifest::from_cbor(&data) {
+        return Ok(manifest);
     }
+
+    Manifest::from_bytes(&data)
+}
+
+fn print_stats(stats: &ManifestStats) {
+    println!("Files:         {}", stats.file_count);
+    println!("Total size:    {} bytes", stats.total_bytes);
+    println!("Chunks:        {}", stats.chunk_count);
+    println!(
+        "Unique chunks: {} ({} bytes)",
+        stats.unique_chunks, stats.unique_bytes
+    );
+    println!("Dedup ratio:   {:.2}", stats.dedup_ratio);
+
+    println!();
+    println!("Chunk sizes:");
+    let widest = stats.histogram.iter().map(|b| b.chunks).max().unwrap_or(0);
+    for bucket in &stats.histogram {
+        let bar = (bucket.chunks * 40).checked_div(widest).unwrap_or(0);
+        println!(
+            "  {:>8} - {:>8} {:>8} {}",
+            bucket.min,
+            bucket.max,
+            bucket.chunks,
+            "#".repeat(bar)
+        );
+    }
+
+    println!();
+    println!("Largest files:");
+    for (path, length) in &stats.largest_files {
+        println!("  {:>12} {}", length, path.display());
+    }
+
+    if !stats.most_shared.is_empty() {
+        println!();
+        println!("Most shared files:");
+        for file in &stats.most_shared {
+            println!(
+                "  {:>6} chunks {:>12} bytes {}",
+                file.shared_chunks,
+                file.shared_bytes,
+                file.path.display()
+            );
+        }
+    }
+}
+
+/// Signs a manifest file, keeping it compressed if it was.
+fn sign(manifest: &Path, key: &Path, output: &Path) -> Result<(), BinsyncError> {
+    let key = signing_key_from_hex(&fs::read_to_string(key)?)?;
+
+    let data = fs::read(manifest)?;
+    let compressed = Manifest::is_compressed(&data);
+
+    // Remote manifests start with a whole manifest, so they are tried first.
+    #[cfg(feature = "network")]
+    if let Ok(mut remote) = binsync::RemoteManifest::from_bytes(&data) {
+        remote.sign(&key)?;
+
+        let data = if compressed {
+            remote.to_compressed_bytes()?
+        } else {
+            remote.to_bytes()?
+        };
+        return Ok(fs::write(output, data)?);
+    }
+
+    let mut manifest = Manifest::from_bytes(&data)?;
+    manifest.sign(&key)?;
+
+    let data = if compressed {
+        manifest.to_compressed_bytes()?
+    } else {
+        manifest.to_bytes()?
+    };
+    Ok(fs::write(output, data)?)
+}
+
+/// Writes a file only the current user can read on platforms that allow it.
+fn write_secret(path: &Path, contents: &str) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
@@ -0,0 +1,185 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use super::{
+    manifest::{FileChunkInfo, Manifest},
+    ChunkId, Digest,
+};
+
+/// The files that changed between two manifests.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct ManifestDiff {
+    /// Files that only exist in the newer manifest.
+    pub added: Vec<FileDiff>,
+    /// Files that only exist in the older manifest.
+    pub removed: Vec<PathBuf>,
+    /// Files in both manifests whose contents differ.
+    pub modified: Vec<FileDiff>,
+    /// Files that moved to a new path with the same contents.
+    pub renamed: Vec<RenamedFile>,
+}
+
+impl ManifestDiff {
+    /// Whether both manifests hold the same files.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+            && self.renamed.is_empty()
+    }
+}
+
+/// How much of a changed file is new compared to the older manifest.
+#[derive(PartialEq, Debug, Clone)]
+pub struct FileDiff {
+    pub path: PathBuf,
+    /// Chunks that are not anywhere in the older manifest.
+    pub new_chunks: usize,
+    /// Chunks that already exist somewhere in the older manifest.
+    pub reused_chunks: usize,
+    /// Bytes that would have to be fetched to sync only this file, counting
+    /// each distinct new chunk once.
+    pub new_bytes: u64,
+}
+
+/// A file that was moved without changing its contents.
+#[derive(PartialEq, Debug, Clone)]
+pub struct RenamedFile {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl Manifest {
+    /// Compares this manifest against a newer one. Only the recorded chunks
+    /// are used so nothing is read from disk. Chunk ids are only comparable
+    /// between manifests with the same header, otherwise every chunk of a
+    /// changed file counts as new.
+    pub fn diff(&self, other: &Manifest) -> ManifestDiff {
+        let old_files: HashMap<&Path, &FileChunkInfo> =
+            self.files.iter().map(|f| (f.path.as_path(), f)).collect();
+        let new_paths: HashSet<&Path> = other.files.iter().map(|f| f.path.as_path()).collect();
+        let old_chunks: HashSet<&ChunkId> = self
+            .files
+            .iter()
+            .flat_map(|f| f.chunks.iter().map(|c| &c.hash))
+            .collect();
+
+        let mut diff = ManifestDiff::default();
+        let mut added = Vec::new();
+
+        for file in &other.files {
+            match old_files.get(file.path.as_path()) {
+                Some(old) if same_contents(old, file) => {}
+                Some(_) => diff.modified.push(file_diff(file, &old_chunks)),
+                None => added.push(file),
+            }
+        }
+
+        let mut removed: Vec<Option<&FileChunkInfo>> = self
+            .files
+            .iter()
+            .filter(|f| !new_paths.contains(f.path.as_path()))
+            .map(Some)
+            .collect();
+
+        // Removed files are indexed by contents, first one on top, so each
+        // added file finds the file it was renamed from in a single lookup.
+        let use_digests = self
+            .files
+            .iter()
+            .chain(&other.files)
+            .all(|f| f.digest.is_some());
+        let mut by_contents: HashMap<ContentKey, Vec<usize>> = HashMap::new();
+        for (index, old) in removed.iter().enumerate().rev() {
+            if let Some(old) = old {
+                by_contents
+                    .entry(content_key(old, use_digests))
+                    .or_default()
+                    .push(index);
+            }
+        }
+
+        // Added files with the same contents as a removed file were renamed.
+        for file in added {
+            let index = by_contents
+                .get_mut(&content_key(file, use_digests))
+                .and_then(|indices| indices.pop());
+
+            match index.and_then(|index| removed[index].take()) {
+                Some(old) => diff.renamed.push(RenamedFile {
+                    from: old.path.clone(),
+                    to: file.path.clone(),
+                }),
+                None => diff.added.push(file_diff(file, &old_chunks)),
+            }
+        }
+
+        diff.removed = removed
+            .into_iter()
+            .flatten()
+            .map(|f| f.path.clone())
+            .collect();
+
+        diff
+    }
+}
+
+/// The contents of a file used to match renames. Whole-file digests are used
+/// when both manifests recorded them and chunk lists for converted manifests.
+#[derive(PartialEq, Eq, Hash)]
+enum ContentKey<'a> {
+    Digest(u64, Digest),
+    Chunks(u64, Vec<&'a ChunkId>),
+}
+
+fn content_key(file: &FileChunkInfo, use_digests: bool) -> ContentKey<'_> {
+    match file.digest {
+        Some(digest) if use_digests => ContentKey::Digest(file.length, digest),
+        _ => ContentKey::Chunks(file.length, file.chunks.iter().map(|c| &c.hash).collect()),
+    }
+}
+
+/// Compares whole-file digests when both manifests recorded them and falls
+/// back to the chunk lists for converted manifests.
+fn same_contents(a: &FileChunkInfo, b: &FileChunkInfo) -> bool {
+    if a.length != b.length {
+        return false;
+    }
+
+    match (a.digest, b.digest) {
+        (Some(a), Some(b)) => a == b,
+        _ => {
+            a.chunks.len() == b.chunks.len()
+                && a.chunks
+                    .iter()
+                    .zip(&b.chunks)
+                    .all(|(a, b)| a.hash == b.hash)
+        }
+    }
+}
+
+fn file_diff(file: &FileChunkInfo, old_chunks: &HashSet<&ChunkId>) -> FileDiff {
+    let mut diff = FileDiff {
+        path: file.path.clone(),
+        new_chunks: 0,
+        reused_chunks: 0,
+        new_bytes: 0,
+    };
+    let mut counted = HashSet::new();
+
+    for chunk in &file.chunks {
+        if old_chunks.contains(&chunk.hash) {
+            diff.reused_chunks += 1;
+        } else {
+            diff.new_chunks += 1;
+
+            if counted.insert(chunk.hash) {
+                diff.new_bytes += chunk.length;
+            }
+        }
+    }
+
+    diff
+}
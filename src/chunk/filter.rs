@@ -0,0 +1,138 @@
+use std::path::Path;
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+
+use crate::BinsyncError;
+
+/// Decides which paths under a root end up in a manifest based on include
+/// and exclude glob patterns.
+///
+/// Patterns are matched against the path relative to the root using `/` as
+/// the separator. `*` does not cross directories while `**` does. A pattern
+/// without a `/` matches the name at any depth so `*.tmp` excludes temporary
+/// files everywhere. Excluding a directory excludes everything inside it.
+pub(crate) struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl PathFilter {
+    pub(crate) fn new(include: &[String], exclude: &[String]) -> Result<PathFilter, BinsyncError> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(build(include)?)
+        };
+
+        Ok(PathFilter {
+            include,
+            exclude: build(exclude)?,
+        })
+    }
+
+    /// Excluded directories are skipped entirely while walking.
+    pub(crate) fn is_excluded(&self, path: &Path) -> bool {
+        self.exclude.is_match(path)
+    }
+
+    /// Whether an entry that is not excluded should be recorded. Every entry
+    /// is included when there are no include patterns.
+    pub(crate) fn is_included(&self, path: &Path) -> bool {
+        self.include.as_ref().is_none_or(|set| set.is_match(path))
+    }
+
+    /// Whether the filter can leave out anything at all.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.include.is_none() && self.exclude.is_empty()
+    }
+}
+
+fn build(patterns: &[String]) -> Result<GlobSet, BinsyncError> {
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        let pattern = pattern.trim_start_matches('/');
+
+        if pattern.contains('/') {
+            builder.add(glob(pattern)?);
+        } else {
+            builder.add(glob(&format!("**/{}", pattern))?);
+        }
+
+        // `dir/**` also matches the directory itself so it is not recorded
+        // as an empty directory.
+        if let Some(directory) = pattern.strip_suffix("/**") {
+            builder.add(glob(directory)?);
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+fn glob(pattern: &str) -> Result<Glob, BinsyncError> {
+    Ok(GlobBuilder::new(pattern).literal_separator(true).build()?)
+}
+
+/// Name of the files holding ignore rules for the directory they are in.
+pub(crate) const IGNORE_FILE: &str = ".binsyncignore";
+
+/// Tracks the `.binsyncignore` files of the directory being walked and all of
+/// its parents. Rules follow gitignore semantics: deeper files take
+/// precedence, later lines take precedence over earlier ones, and `!` re-includes
+/// a path unless one of its parent directories is ignored.
+pub(crate) struct IgnoreStack {
+    stack: Vec<(usize, Gitignore)>,
+}
+
+impl IgnoreStack {
+    pub(crate) fn new() -> IgnoreStack {
+        IgnoreStack { stack: Vec::new() }
+    }
+
+    /// Loads the ignore file of a directory at the given depth, if it has
+    /// one. Must be called before any of the directory's entries are checked.
+    pub(crate) fn enter(&mut self, directory: &Path, depth: usize) -> Result<(), BinsyncError> {
+        let path = directory.join(IGNORE_FILE);
+        if !path.is_file() {
+            return Ok(());
+        }
+
+        let invalid = |err: ignore::Error| BinsyncError::InvalidIgnoreFile {
+            path: path.clone(),
+            reason: err.to_string(),
+        };
+
+        let mut builder = GitignoreBuilder::new(directory);
+        if let Some(err) = builder.add(&path) {
+            return Err(invalid(err));
+        }
+
+        let gitignore = builder.build().map_err(invalid)?;
+        self.stack.push((depth, gitignore));
+
+        Ok(())
+    }
+
+    /// Checks an entry at the given depth against every ignore file above it.
+    /// Entries have to be checked in walk order so that ignore files of
+    /// directories that were already left can be dropped.
+    pub(crate) fn is_ignored(&mut self, path: &Path, depth: usize, is_dir: bool) -> bool {
+        while self.stack.last().is_some_and(|(d, _)| *d >= depth) {
+            self.stack.pop();
+        }
+
+        for (_, gitignore) in self.stack.iter().rev() {
+            match gitignore.matched(path, is_dir) {
+                Match::None => continue,
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+
+        false
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use crate::{BinsyncError, DirectoryInfo, FileChunkInfo, LinkInfo, LinkKind, TrustedKeys};
+
+use super::manifest::Manifest;
+
+/// A named manifest that is part of a `LayeredManifest`.
+#[derive(PartialEq, Debug)]
+pub struct Layer {
+    pub name: String,
+    pub manifest: Manifest,
+}
+
+/// Combines a base manifest with overlays that are synced into the same
+/// destination, such as optional content or language packs. When layers hold
+/// the same path the layer added last wins, whatever kind of entry it is. A
+/// file or link replacing a directory also hides everything that lower
+/// layers had inside that directory.
+#[derive(PartialEq, Debug)]
+pub struct LayeredManifest {
+    layers: Vec<Layer>,
+}
+
+/// An entry of the merged view.
+enum Entry<'a> {
+    File(&'a FileChunkInfo),
+    Directory(&'a DirectoryInfo),
+    Link(&'a LinkInfo),
+}
+
+impl LayeredManifest {
+    pub fn new<S: Into<String>>(name: S, base: Manifest) -> LayeredManifest {
+        LayeredManifest {
+            layers: vec![Layer {
+                name: name.into(),
+                manifest: base,
+            }],
+        }
+    }
+
+    /// Adds an overlay on top of every existing layer. Layers need unique
+    /// names and have to be chunked the same way as the base so one pass
+    /// over the destination works for all of them.
+    pub fn push<S: Into<String>>(
+        &mut self,
+        name: S,
+        manifest: Manifest,
+    ) -> Result<(), BinsyncError> {
+        let name = name.into();
+
+        if self.layers.iter().any(|layer| layer.name == name) {
+            return Err(BinsyncError::InvalidLayer(name));
+        }
+
+        let base = &self.layers[0].manifest.header;
+        if manifest.header.chunking != base.chunking
+            || manifest.header.chunker != base.chunker
+            || manifest.header.hash != base.hash
+        {
+            return Err(BinsyncError::InvalidLayer(name));
+        }
+
+        self.layers.push(Layer { name, manifest });
+
+        Ok(())
+    }
+
+    /// The layers from the base up.
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// The name of the layer a path in the merged view comes from. Use
+    /// `owners` when looking up many paths.
+    pub fn owner<P: AsRef<Path>>(&self, path: P) -> Option<&str> {
+        self.merge()
+            .get(path.as_ref())
+            .map(|(layer, _)| self.layers[*layer].name.as_str())
+    }
+
+    /// The name of the owning layer of every path in the merged view.
+    pub fn owners(&self) -> HashMap<PathBuf, String> {
+        self.merge()
+            .into_iter()
+            .map(|(path, (layer, _))| (path.to_path_buf(), self.layers[layer].name.clone()))
+            .collect()
+    }
+
+    /// Checks every layer was signed by one of the trusted keys.
+    pub fn verify_signatures(&self, keys: &TrustedKeys) -> Result<(), BinsyncError> {
+        for layer in &self.layers {
+            layer.manifest.verify_signature(keys)?;
+        }
+
+        Ok(())
+    }
+
+    /// Builds a single manifest of the merged view. It has the header of the
+    /// base layer and no signature.
+    pub fn flatten(&self) -> Manifest {
+        let mut manifest = Manifest::new();
+        manifest.header = self.layers[0].manifest.header.clone();
+
+        let merged = self.merge();
+
+        for (path, (layer, entry)) in &merged {
+            match entry {
+                Entry::File(file) => manifest.files.push((*file).clone()),
+                Entry::Directory(directory) => manifest.directories.push((*directory).clone()),
+                Entry::Link(link) => {
+                    let primary = match &link.kind {
+                        LinkKind::Hardlink(primary) => primary,
+                        LinkKind::Symlink(_) => {
+                            manifest.links.push((*link).clone());
+                            continue;
+                        }
+                    };
+
+                    let same_layer = matches!(
+                        merged.get(primary.as_path()),
+                        Some((owner, Entry::File(_))) if owner == layer
+                    );
+                    if same_layer {
+                        manifest.links.push((*link).clone());
+                        continue;
+                    }
+
+                    // The file this was linked to has been replaced by another
+                    // layer, so it becomes a copy of the original instead.
+                    let original = self.layers[*layer]
+                        .manifest
+                        .files
+                        .iter()
+                        .find(|file| file.path == *primary);
+
+                    if let Some(original) = original {
+                        manifest.files.push(FileChunkInfo {
+                            path: path.to_path_buf(),
+                            ..original.clone()
+                        });
+                    }
+                }
+            }
+        }
+
+        manifest.files.sort_by_cached_key(|k| k.path.clone());
+
+        manifest
+    }
+
+    /// Merges every layer in order so later layers replace earlier ones.
+    fn merge(&self) -> BTreeMap<&Path, (usize, Entry<'_>)> {
+        let mut merged = BTreeMap::new();
+
+        for (index, layer) in self.layers.iter().enumerate() {
+            let manifest = &layer.manifest;
+
+            for file in &manifest.files {
+                merged.insert(file.path.as_path(), (index, Entry::File(file)));
+            }
+
+            for directory in &manifest.directories {
+                merged.insert(
+                    directory.path.as_path(),
+                    (index, Entry::Directory(directory)),
+                );
+            }
+
+            for link in &manifest.links {
+                merged.insert(link.path.as_path(), (index, Entry::Link(link)));
+            }
+        }
+
+        // Entries can not live inside something that is no longer a directory.
+        let hidden: Vec<&Path> = merged
+            .keys()
+            .filter(|path| {
+                path.ancestors().skip(1).any(|parent| {
+                    matches!(
+                        merged.get(parent),
+                        Some((_, Entry::File(_) | Entry::Link(_)))
+                    )
+                })
+            })
+            .copied()
+            .collect();
+
+        for path in hidden {
+            merged.remove(path);
+        }
+
+        merged
+    }
+}
@@ -0,0 +1,104 @@
+//! Layouts from before manifests had a header. These are only ever read and
+//! are converted into the current types straight away.
+
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use super::{
+    manifest::{FileChunkInfo, FileMetadata, Manifest, ManifestHeader},
+    Chunk, ChunkId, ChunkerConfig, Digest, HashAlgorithm,
+};
+
+/// Chunk ids used to be the first 8 bytes of an MD5 digest as a `u64`.
+fn widen(hash: u64) -> ChunkId {
+    let mut id = [0; 32];
+    id[..8].copy_from_slice(&hash.to_le_bytes());
+
+    Digest(id)
+}
+
+#[derive(Deserialize)]
+struct LegacyChunk {
+    hash: u64,
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Deserialize)]
+struct LegacyFileChunkInfo {
+    path: PathBuf,
+    chunks: Vec<LegacyChunk>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LegacyManifest {
+    files: Vec<LegacyFileChunkInfo>,
+}
+
+impl From<LegacyManifest> for Manifest {
+    fn from(legacy: LegacyManifest) -> Self {
+        let mut manifest = Manifest::new();
+        manifest.header =
+            ManifestHeader::new(ChunkerConfig::default(), HashAlgorithm::Md5Truncated);
+
+        for file in legacy.files {
+            let chunks: Vec<Chunk> = file
+                .chunks
+                .into_iter()
+                .map(|chunk| Chunk {
+                    hash: widen(chunk.hash),
+                    offset: chunk.offset,
+                    length: chunk.length,
+                    zero: false,
+                })
+                .collect();
+
+            // There was no whole file digest back then.
+            manifest.files.push(FileChunkInfo {
+                path: file.path,
+                length: chunks.iter().map(|c| c.length).sum(),
+                digest: None,
+                chunks,
+                metadata: FileMetadata::default(),
+            });
+        }
+
+        manifest
+    }
+}
+
+#[cfg(feature = "network")]
+#[derive(Deserialize)]
+struct LegacyPack {
+    hash: u64,
+    length: u64,
+    chunks: Vec<u64>,
+}
+
+#[cfg(feature = "network")]
+#[derive(Deserialize)]
+pub(crate) struct LegacyRemoteManifest {
+    source: LegacyManifest,
+    packs: Vec<LegacyPack>,
+}
+
+#[cfg(feature = "network")]
+impl From<LegacyRemoteManifest> for super::network::RemoteManifest {
+    fn from(legacy: LegacyRemoteManifest) -> Self {
+        let packs = legacy
+            .packs
+            .into_iter()
+            .map(|pack| super::network::Pack {
+                hash: widen(pack.hash),
+                length: pack.length,
+                chunks: pack.chunks.into_iter().map(widen).collect(),
+            })
+            .collect();
+
+        super::network::RemoteManifest {
+            source: legacy.source.into(),
+            packs,
+        }
+    }
+}
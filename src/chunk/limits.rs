@@ -0,0 +1,156 @@
+use std::{borrow::Cow, io::Read};
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+
+use crate::BinsyncError;
+
+use super::manifest::{Manifest, COMPRESSED_MAGIC};
+
+/// Bounds on what a manifest from an untrusted source may hold, so a hostile
+/// or corrupt manifest fails to load instead of exhausting memory. The
+/// defaults are far beyond any real install.
+#[derive(Clone, Copy, Debug)]
+pub struct ManifestLimits {
+    /// Largest serialized manifest accepted, checked both before and after
+    /// decompression.
+    pub max_bytes: u64,
+    /// Most files, directories and links a manifest may list in total.
+    pub max_entries: usize,
+    /// Largest sum of all file lengths.
+    pub max_total_size: u64,
+    /// Largest pack of a remote manifest, which is how much of a single
+    /// download is held in memory.
+    pub max_pack_size: u64,
+    /// Most chunks a single pack of a remote manifest may hold.
+    pub max_pack_chunks: usize,
+}
+
+impl Default for ManifestLimits {
+    fn default() -> Self {
+        ManifestLimits {
+            max_bytes: 1 << 30,      // 1GB
+            max_entries: 10_000_000, // 10 million
+            max_total_size: 1 << 44, // 16TB
+            max_pack_size: 1 << 30,  // 1GB
+            // Enough for the default 4MB pack filled with one byte files.
+            max_pack_chunks: 1 << 22,
+        }
+    }
+}
+
+impl ManifestLimits {
+    /// Unwraps the compressed container without producing more than
+    /// `max_bytes`. Data that is not compressed is only checked for length.
+    pub(crate) fn decompress<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>, BinsyncError> {
+        self.check_size(data.len())?;
+
+        let compressed = match data.strip_prefix(&COMPRESSED_MAGIC) {
+            Some(compressed) => compressed,
+            None => return Ok(Cow::Borrowed(data)),
+        };
+
+        let mut decompressed = Vec::new();
+        zstd::stream::read::Decoder::new(compressed)?
+            .take(self.max_bytes + 1)
+            .read_to_end(&mut decompressed)?;
+
+        if decompressed.len() as u64 > self.max_bytes {
+            return Err(invalid(
+                "manifest is larger than the limit once decompressed",
+            ));
+        }
+
+        Ok(Cow::Owned(decompressed))
+    }
+
+    /// Checks the length of serialized data before it is parsed.
+    pub(crate) fn check_size(&self, length: usize) -> Result<(), BinsyncError> {
+        if length as u64 > self.max_bytes {
+            return Err(invalid("manifest is larger than the limit"));
+        }
+
+        Ok(())
+    }
+
+    /// Deserializes the same layout as `bincode::deserialize` without reading
+    /// more than `max_bytes`.
+    pub(crate) fn deserialize<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, BinsyncError> {
+        Ok(bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(self.max_bytes)
+            .deserialize(data)?)
+    }
+
+    /// Checks the contents of a loaded manifest against the limits and that
+    /// its chunks are consistent.
+    pub(crate) fn check(&self, manifest: &Manifest) -> Result<(), BinsyncError> {
+        let entries = manifest.files.len() + manifest.directories.len() + manifest.links.len();
+        if entries > self.max_entries {
+            return Err(invalid("manifest has more entries than the limit"));
+        }
+
+        check_chunks(manifest)?;
+
+        for file in &manifest.files {
+            if file
+                .metadata
+                .modified
+                .is_some_and(|m| m.nanos >= 1_000_000_000)
+            {
+                return Err(invalid(format!(
+                    "modified time of {} has more than a second of nanoseconds",
+                    file.path.display()
+                )));
+            }
+        }
+
+        let total = manifest
+            .files
+            .iter()
+            .try_fold(0u64, |total, file| total.checked_add(file.length));
+        if total.is_none_or(|total| total > self.max_total_size) {
+            return Err(invalid("manifest files are larger than the limit"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Makes sure every chunk fits the maximum chunk size in the header and that
+/// the chunks of each file add up to its length. Chunk lengths decide how
+/// much memory is allocated while syncing.
+pub(crate) fn check_chunks(manifest: &Manifest) -> Result<(), BinsyncError> {
+    let max = manifest.header.chunker.max_size as u64;
+
+    for file in &manifest.files {
+        let mut length: u64 = 0;
+
+        for chunk in &file.chunks {
+            if chunk.length > max {
+                return Err(invalid(format!(
+                    "chunk {} of {} is longer than {} bytes",
+                    chunk.hash,
+                    file.path.display(),
+                    max
+                )));
+            }
+
+            length += chunk.length;
+        }
+
+        if length != file.length {
+            return Err(invalid(format!(
+                "chunks of {} do not add up to its length",
+                file.path.display()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn invalid<S: Into<String>>(reason: S) -> BinsyncError {
+    BinsyncError::InvalidManifest(reason.into())
+}
@@ -1,25 +1,369 @@
 use std::{
-    convert::TryInto,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fs::{File, Metadata},
+    io::{self, Read},
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::mpsc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use fastcdc::FastCDC;
-use serde::{Deserialize, Serialize};
+use ed25519_dalek::SigningKey;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use walkdir::WalkDir;
 
 use crate::{
     chunk::{FileInfo, FileList},
     sync::ThreadPool,
+    BinsyncError,
 };
 
-use super::{Chunk, AVG_CHUNK, MAX_CHUNK, MIN_CHUNK};
+use super::{
+    filter::{IgnoreStack, PathFilter, IGNORE_FILE},
+    legacy::LegacyManifest,
+    limits::ManifestLimits,
+    path::portable,
+    signing::{ManifestSignature, TrustedKeys},
+    stream::StreamChunker,
+    Chunk, ChunkerConfig, ChunkingAlgorithm, Digest, HashAlgorithm,
+};
+
+/// Every serialized manifest starts with these bytes. Manifests from before
+/// the header existed start with their file count instead.
+pub(crate) const MANIFEST_MAGIC: [u8; 8] = *b"BINSYNC\0";
+
+/// Marks a compressed manifest. The rest of the data is a zstd frame holding
+/// the manifest as written by `to_bytes`.
+pub(crate) const COMPRESSED_MAGIC: [u8; 8] = *b"BINSYNCZ";
+
+/// Compression level used for compressed manifests.
+const COMPRESSION_LEVEL: i32 = 9;
+
+/// Version of the manifest layout written by this crate. Only bumped once per
+/// release that changes the layout, since every released version has to stay
+/// readable or be rejected with a clear error.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Each generation thread buffers this many maximum sized chunks of a file
+/// so memory use does not depend on the size of the files.
+const BUFFERED_CHUNKS: usize = 4;
 
 /// Information about a file and which chunks it contains.
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct FileChunkInfo {
+    #[serde(with = "portable")]
     pub path: PathBuf,
+    /// Total length of the file in bytes.
+    pub length: u64,
+    /// Digest of the whole file using the manifest hash algorithm. Only
+    /// missing for manifests converted from before it was recorded.
+    pub digest: Option<Digest>,
     pub chunks: Vec<Chunk>,
+    pub metadata: FileMetadata,
+}
+
+/// Permissions and modification time of a file. Fields are optional since
+/// not every platform can capture them.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+pub struct FileMetadata {
+    /// Unix permission bits including the executable bits.
+    pub mode: Option<u32>,
+    pub modified: Option<Timestamp>,
+    /// Inode number of the source file. Only used to detect changes when
+    /// updating a manifest and never applied to the destination.
+    pub inode: Option<u64>,
+}
+
+impl FileMetadata {
+    /// Captures the metadata we care about from the file system.
+    pub fn from_metadata(metadata: &Metadata) -> FileMetadata {
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o7777)
+        };
+
+        #[cfg(unix)]
+        let inode = {
+            use std::os::unix::fs::MetadataExt;
+            Some(metadata.ino())
+        };
+
+        #[cfg(not(unix))]
+        let (mode, inode) = (None, None);
+
+        FileMetadata {
+            mode,
+            modified: metadata.modified().ok().map(Timestamp::from),
+            inode,
+        }
+    }
+}
+
+/// A point in time relative to the Unix epoch. Used instead of `SystemTime`
+/// so the manifest serializes the same on every platform.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Timestamp {
+    pub seconds: i64,
+    pub nanos: u32,
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => Timestamp {
+                seconds: duration.as_secs() as i64,
+                nanos: duration.subsec_nanos(),
+            },
+            Err(err) => {
+                // Before the epoch so count backwards keeping nanos positive.
+                let duration = err.duration();
+                let mut seconds = -(duration.as_secs() as i64);
+                let mut nanos = duration.subsec_nanos();
+                if nanos > 0 {
+                    seconds -= 1;
+                    nanos = 1_000_000_000 - nanos;
+                }
+
+                Timestamp { seconds, nanos }
+            }
+        }
+    }
+}
+
+impl TryFrom<Timestamp> for SystemTime {
+    type Error = BinsyncError;
+
+    /// Fails when the platform can not represent the time.
+    fn try_from(timestamp: Timestamp) -> Result<Self, Self::Error> {
+        let nanos = Duration::from_nanos(timestamp.nanos as u64);
+        let seconds = Duration::from_secs(timestamp.seconds.unsigned_abs());
+
+        let time = if timestamp.seconds >= 0 {
+            UNIX_EPOCH.checked_add(seconds)
+        } else {
+            UNIX_EPOCH.checked_sub(seconds)
+        };
+
+        time.and_then(|time| time.checked_add(nanos))
+            .ok_or_else(|| {
+                BinsyncError::InvalidManifest(format!(
+                    "modified time {}.{:09} is out of range",
+                    timestamp.seconds, timestamp.nanos
+                ))
+            })
+    }
+}
+
+/// A directory in the source. Directories are recorded on their own so empty
+/// folders make it to the destination.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct DirectoryInfo {
+    #[serde(with = "portable")]
+    pub path: PathBuf,
+}
+
+/// A link in the source that is recreated in the destination instead of
+/// having its contents copied.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct LinkInfo {
+    #[serde(with = "portable")]
+    pub path: PathBuf,
+    pub kind: LinkKind,
+}
+
+/// The kinds of links a manifest can hold.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum LinkKind {
+    /// A symbolic link and the target it points to, exactly as stored on disk.
+    Symlink(#[serde(with = "portable")] PathBuf),
+    /// A hard link to another file in the manifest. Every hard link group has
+    /// one regular file entry and the rest of the group points at it.
+    Hardlink(#[serde(with = "portable")] PathBuf),
+}
+
+/// Settings used when generating a manifest.
+#[derive(Clone, Debug)]
+pub struct ManifestOptions {
+    /// Follows symbolic links and treats hard links as separate files so that
+    /// everything is stored as regular files and directories.
+    pub follow_links: bool,
+
+    /// Chunk sizes to use. Larger chunks suit large archives while smaller
+    /// chunks find more reuse between small files. Recorded in the header.
+    pub chunker: ChunkerConfig,
+
+    /// Glob patterns of paths to record, relative to the root. Everything is
+    /// recorded when empty. Only used when walking a path.
+    pub include: Vec<String>,
+
+    /// Glob patterns of paths to leave out, relative to the root. Takes
+    /// precedence over `include` and excludes the contents of directories.
+    pub exclude: Vec<String>,
+
+    /// Honours `.binsyncignore` files found while walking a path. The ignore
+    /// files themselves are never recorded. Enabled by default.
+    pub ignore_files: bool,
+
+    /// When updating a manifest, also treats a file as changed if its inode
+    /// differs. Catches files replaced by another file with the same size
+    /// and modification time, such as when restored from a backup.
+    pub compare_inodes: bool,
+
+    /// Records paths that only differ by case or Unicode normalization
+    /// instead of failing. Such paths overwrite each other when synced to
+    /// Windows or macOS.
+    pub allow_path_collisions: bool,
+}
+
+impl Default for ManifestOptions {
+    fn default() -> Self {
+        ManifestOptions {
+            follow_links: false,
+            chunker: ChunkerConfig::default(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            ignore_files: true,
+            compare_inodes: false,
+            allow_path_collisions: false,
+        }
+    }
+}
+
+/// Describes how a manifest was produced so a consumer knows if it is able to
+/// read and sync it. The consumer has to chunk and hash existing files the
+/// same way the producer did for chunks to be reused.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ManifestHeader {
+    #[serde(with = "magic")]
+    magic: [u8; 8],
+    pub version: u32,
+    pub chunking: ChunkingAlgorithm,
+    pub chunker: ChunkerConfig,
+    pub hash: HashAlgorithm,
+}
+
+impl ManifestHeader {
+    pub fn new(chunker: ChunkerConfig, hash: HashAlgorithm) -> ManifestHeader {
+        ManifestHeader {
+            magic: MANIFEST_MAGIC,
+            version: FORMAT_VERSION,
+            chunking: ChunkingAlgorithm::FastCdc,
+            chunker,
+            hash,
+        }
+    }
+
+    /// Makes sure this version of the crate is able to sync a manifest with
+    /// this header.
+    pub fn validate(&self) -> Result<(), BinsyncError> {
+        if self.magic != MANIFEST_MAGIC || self.version != FORMAT_VERSION {
+            return Err(BinsyncError::UnsupportedVersion(self.version));
+        }
+
+        self.chunker.validate()
+    }
+
+    /// Reads only the header from serialized data. Returns `None` for data
+    /// written before manifests had a header.
+    pub(crate) fn peek(data: &[u8]) -> Result<Option<ManifestHeader>, BinsyncError> {
+        if !data.starts_with(&MANIFEST_MAGIC) {
+            return Ok(None);
+        }
+
+        // The version comes before anything that may change between versions
+        // so it is checked on its own first.
+        let (_, version): ([u8; 8], u32) = bincode::deserialize(data)?;
+        if version != FORMAT_VERSION {
+            return Err(BinsyncError::UnsupportedVersion(version));
+        }
+
+        let header: ManifestHeader = bincode::deserialize(data)?;
+        header.validate()?;
+
+        Ok(Some(header))
+    }
+}
+
+impl Default for ManifestHeader {
+    fn default() -> Self {
+        Self::new(ChunkerConfig::default(), HashAlgorithm::Blake3)
+    }
+}
+
+/// Writes the magic as a string in human readable formats so it does not
+/// show up as a list of numbers.
+mod magic {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::MANIFEST_MAGIC;
+
+    const NAME: &str = "BINSYNC";
+
+    pub fn serialize<S: Serializer>(magic: &[u8; 8], serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return magic.serialize(serializer);
+        }
+
+        if *magic == MANIFEST_MAGIC {
+            serializer.serialize_str(NAME)
+        } else {
+            magic.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 8], D::Error> {
+        if !deserializer.is_human_readable() {
+            return <[u8; 8]>::deserialize(deserializer);
+        }
+
+        // Anything else fails header validation.
+        let name = String::deserialize(deserializer)?;
+        Ok(if name == NAME { MANIFEST_MAGIC } else { [0; 8] })
+    }
+}
+
+/// Wraps serialized manifest data in the compressed container.
+pub(crate) fn compress(data: &[u8]) -> Result<Vec<u8>, BinsyncError> {
+    let mut compressed = COMPRESSED_MAGIC.to_vec();
+    zstd::stream::copy_encode(data, &mut compressed, COMPRESSION_LEVEL)?;
+
+    Ok(compressed)
+}
+
+/// Reads JSON written by one of the `to_json` functions. The version at the
+/// given JSON pointer is checked before anything that may change between
+/// versions is read.
+pub(crate) fn from_json<T: DeserializeOwned>(json: &str, version: &str) -> Result<T, BinsyncError> {
+    ManifestLimits::default().check_size(json.len())?;
+
+    let value: serde_json::Value = serde_json::from_str(json)?;
+
+    let version = value
+        .pointer(version)
+        .and_then(|v| v.as_u64())
+        .map_or(0, |v| u32::try_from(v).unwrap_or(u32::MAX));
+    if version != FORMAT_VERSION {
+        return Err(BinsyncError::UnsupportedVersion(version));
+    }
+
+    Ok(serde_json::from_value(value)?)
+}
+
+#[cfg(feature = "cbor")]
+pub(crate) fn to_cbor<T: Serialize>(value: &T) -> Result<Vec<u8>, BinsyncError> {
+    let mut data = Vec::new();
+    ciborium::ser::into_writer(value, &mut data)
+        .map_err(|err| BinsyncError::CborError(err.to_string()))?;
+
+    Ok(data)
+}
+
+#[cfg(feature = "cbor")]
+pub(crate) fn from_cbor<T: DeserializeOwned>(data: &[u8]) -> Result<T, BinsyncError> {
+    ManifestLimits::default().check_size(data.len())?;
+
+    ciborium::de::from_reader(data).map_err(|err| BinsyncError::CborError(err.to_string()))
 }
 
 /// Holds a list of files and which chunks exist inside those files in which
@@ -27,88 +371,627 @@ pub struct FileChunkInfo {
 /// we should be syncing to.
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct Manifest {
+    pub header: ManifestHeader,
     pub files: Vec<FileChunkInfo>,
+    pub directories: Vec<DirectoryInfo>,
+    pub links: Vec<LinkInfo>,
+    /// Signature over everything above it. See `Manifest::sign`.
+    pub signature: Option<ManifestSignature>,
 }
 
 impl Manifest {
     pub fn new() -> Manifest {
-        Manifest { files: Vec::new() }
+        Manifest {
+            header: ManifestHeader::default(),
+            files: Vec::new(),
+            directories: Vec::new(),
+            links: Vec::new(),
+            signature: None,
+        }
+    }
+
+    /// Signs the manifest with an Ed25519 key, replacing any previous
+    /// signature. Changing the manifest afterwards invalidates the signature.
+    pub fn sign(&mut self, key: &SigningKey) -> Result<(), BinsyncError> {
+        self.signature = Some(ManifestSignature::sign(key, &self.signed_bytes()?));
+
+        Ok(())
+    }
+
+    /// Checks the manifest was signed by one of the trusted keys and has not
+    /// been changed since.
+    pub fn verify_signature(&self, keys: &TrustedKeys) -> Result<(), BinsyncError> {
+        keys.verify(&self.signed_bytes()?, self.signature.as_ref())
+    }
+
+    /// The serialized manifest without the signature.
+    fn signed_bytes(&self) -> Result<Vec<u8>, BinsyncError> {
+        let unsigned = (&self.header, &self.files, &self.directories, &self.links);
+        Ok(bincode::serialize(&unsigned)?)
+    }
+
+    /// Reads a manifest serialized with `to_bytes` or `to_compressed_bytes`.
+    /// Manifests written before the header existed are converted to the
+    /// current layout. The default `ManifestLimits` apply.
+    pub fn from_bytes(data: &[u8]) -> Result<Manifest, BinsyncError> {
+        Manifest::from_bytes_with_limits(data, &ManifestLimits::default())
+    }
+
+    /// Similar to from_bytes with custom limits. Use tighter limits for
+    /// manifests fetched from somewhere that is not fully trusted.
+    pub fn from_bytes_with_limits(
+        data: &[u8],
+        limits: &ManifestLimits,
+    ) -> Result<Manifest, BinsyncError> {
+        let data = limits.decompress(data)?;
+
+        let manifest = match ManifestHeader::peek(&data)? {
+            Some(_) => limits.deserialize(&data)?,
+            None => limits.deserialize::<LegacyManifest>(&data)?.into(),
+        };
+        limits.check(&manifest)?;
+
+        Ok(manifest)
+    }
+
+    /// Serializes the manifest into the format read by `from_bytes`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BinsyncError> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// Whether the data is in the compressed container written by
+    /// `to_compressed_bytes`, for this or a remote manifest.
+    pub fn is_compressed(data: &[u8]) -> bool {
+        data.starts_with(&COMPRESSED_MAGIC)
+    }
+
+    /// Serializes the manifest like `to_bytes` and compresses it with zstd.
+    /// Chunk ids do not compress but paths and offsets shrink a lot.
+    pub fn to_compressed_bytes(&self) -> Result<Vec<u8>, BinsyncError> {
+        compress(&self.to_bytes()?)
+    }
+
+    /// Reads a manifest written by `to_json`.
+    pub fn from_json(json: &str) -> Result<Manifest, BinsyncError> {
+        let manifest: Manifest = from_json(json, "/header/version")?;
+        manifest.header.validate()?;
+        ManifestLimits::default().check(&manifest)?;
+
+        Ok(manifest)
+    }
+
+    /// Serializes the manifest as pretty printed JSON so it can be inspected
+    /// and compared with standard tools. See the README for the schema.
+    pub fn to_json(&self) -> Result<String, BinsyncError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Reads a manifest written by `to_cbor`.
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(data: &[u8]) -> Result<Manifest, BinsyncError> {
+        let manifest: Manifest = from_cbor(data)?;
+        manifest.header.validate()?;
+        ManifestLimits::default().check(&manifest)?;
+
+        Ok(manifest)
+    }
+
+    /// Serializes the manifest as CBOR with the same layout as `to_json`.
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Result<Vec<u8>, BinsyncError> {
+        to_cbor(self)
     }
 
     /// Generates a manifest using the specified path as the root.
-    pub fn from_path<P: AsRef<Path>>(path: P) -> Manifest {
-        let mut list = FileList { files: Vec::new() };
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Manifest, BinsyncError> {
+        Manifest::from_path_with_options(path, &ManifestOptions::default())
+    }
 
-        let prefix = path.as_ref().to_path_buf();
+    /// Similar to from_path with custom generation options.
+    pub fn from_path_with_options<P: AsRef<Path>>(
+        path: P,
+        options: &ManifestOptions,
+    ) -> Result<Manifest, BinsyncError> {
+        let prefix = path.as_ref();
+        let list = list_files(prefix, options, None)?;
 
-        // TODO: Handle empty folders.
+        generate(prefix, &list, options, None, None)
+    }
 
-        for entry in WalkDir::new(&prefix) {
-            let info = entry.unwrap();
+    /// Regenerates a manifest for a path that a previous manifest was
+    /// generated from. Files whose size and modification time have not
+    /// changed reuse their chunks from the previous manifest instead of being
+    /// read again, so the result matches a full rebuild as long as the
+    /// modification times are trustworthy.
+    pub fn update_from_path<P: AsRef<Path>>(
+        previous: &Manifest,
+        path: P,
+    ) -> Result<Manifest, BinsyncError> {
+        Manifest::update_from_path_with_options(previous, path, &ManifestOptions::default())
+    }
 
-            if info.file_type().is_file() {
-                let name = info.file_name().to_string_lossy().to_string();
-                let directory = info.path().strip_prefix(&prefix).map_or_else(
-                    |_| info.path().to_string_lossy().to_string(),
-                    |p| p.to_string_lossy().to_string(),
-                );
+    /// Similar to update_from_path with custom generation options. Nothing
+    /// is reused if the options chunk differently than the previous manifest.
+    pub fn update_from_path_with_options<P: AsRef<Path>>(
+        previous: &Manifest,
+        path: P,
+        options: &ManifestOptions,
+    ) -> Result<Manifest, BinsyncError> {
+        let prefix = path.as_ref();
+        let list = list_files(prefix, options, None)?;
 
-                list.files.push(FileInfo { name, directory });
-            }
-        }
+        generate(prefix, &list, options, Some(previous), None)
+    }
+
+    /// Similar to from_path_with_options except files that cannot be read
+    /// are left out of the manifest and returned alongside it, as are
+    /// directories whose ignore file fails to load. Only fails if the root
+    /// itself cannot be read.
+    pub fn from_path_skipping_errors<P: AsRef<Path>>(
+        path: P,
+        options: &ManifestOptions,
+    ) -> Result<(Manifest, Vec<SkippedFile>), BinsyncError> {
+        let prefix = path.as_ref();
+        let mut skipped = Vec::new();
+
+        let list = list_files(prefix, options, Some(&mut skipped))?;
+        let manifest = generate(prefix, &list, options, None, Some(&mut skipped))?;
 
-        Manifest::from_file_list(path, &list)
+        Ok((manifest, skipped))
     }
 
     /// Generates a manifest of specific files using the specified path as the
     /// base path. Use this if you want to filter only to specific files in the
     /// directory.
-    pub fn from_file_list<P: AsRef<Path>>(path: P, file_list: &FileList) -> Manifest {
-        let manifest = Arc::new(Mutex::new(Manifest::new()));
-        let prefix = path.as_ref().to_path_buf();
+    pub fn from_file_list<P: AsRef<Path>>(
+        path: P,
+        file_list: &FileList,
+    ) -> Result<Manifest, BinsyncError> {
+        Manifest::from_file_list_with_options(path, file_list, &ManifestOptions::default())
+    }
+
+    /// Similar to from_file_list with custom generation options.
+    pub fn from_file_list_with_options<P: AsRef<Path>>(
+        path: P,
+        file_list: &FileList,
+        options: &ManifestOptions,
+    ) -> Result<Manifest, BinsyncError> {
+        generate(path.as_ref(), file_list, options, None, None)
+    }
+}
 
-        let pool = ThreadPool::new(4);
+/// A file that was left out of a manifest because it could not be read.
+#[derive(Debug)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub error: io::Error,
+}
+
+impl From<SkippedFile> for BinsyncError {
+    fn from(skipped: SkippedFile) -> Self {
+        BinsyncError::ReadFailed {
+            path: skipped.path,
+            source: skipped.error,
+        }
+    }
+}
+
+/// Fails with an ignore file that could not be loaded or adds it to the
+/// skipped files when skipping errors.
+fn skip_ignore_file(
+    skipped: &mut Option<&mut Vec<SkippedFile>>,
+    err: BinsyncError,
+) -> Result<(), BinsyncError> {
+    match (skipped, err) {
+        (Some(skipped), BinsyncError::InvalidIgnoreFile { path, reason }) => {
+            let error = io::Error::new(io::ErrorKind::InvalidData, reason);
+            skipped.push(SkippedFile { path, error });
+            Ok(())
+        }
+        (_, err) => Err(err),
+    }
+}
+
+/// Fails with the given file or adds it to the skipped files when skipping
+/// errors.
+fn skip_or_fail(
+    skipped: &mut Option<&mut Vec<SkippedFile>>,
+    file: SkippedFile,
+) -> Result<(), BinsyncError> {
+    match skipped {
+        Some(skipped) => {
+            skipped.push(file);
+            Ok(())
+        }
+        None => Err(file.into()),
+    }
+}
 
-        for file_info in &file_list.files {
-            let key = file_info.directory.clone();
-            let path = prefix.join(Path::new(&file_info.directory));
-            let manifest = Arc::clone(&manifest);
+/// Walks the path building a list of everything inside of it.
+fn list_files(
+    prefix: &Path,
+    options: &ManifestOptions,
+    mut skipped: Option<&mut Vec<SkippedFile>>,
+) -> Result<FileList, BinsyncError> {
+    let mut list = FileList {
+        files: Vec::new(),
+        directories: Vec::new(),
+        links: Vec::new(),
+    };
 
-            pool.execute(move || {
-                let contents = std::fs::read(path).unwrap();
-                let chunker = FastCDC::new(&contents, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK);
+    let filter = PathFilter::new(&options.include, &options.exclude)?;
 
-                let mut file_chunk_info = FileChunkInfo {
-                    path: PathBuf::from(key),
-                    chunks: Vec::new(),
-                };
+    // Like an unreadable root, a broken ignore file at the root leaves
+    // nothing to generate.
+    let mut ignores = IgnoreStack::new();
+    if options.ignore_files {
+        ignores.enter(prefix, 0)?;
+    }
 
-                for entry in chunker {
-                    let end = entry.offset + entry.length;
-                    let chunk = &contents[entry.offset..end];
+    // Ignore files are loaded while walking so errors are held until the
+    // walker yields its next entry. Directories whose ignore file fails to
+    // load are left out since their contents can not be filtered.
+    let ignore_errors = RefCell::new(Vec::new());
 
-                    let digest = md5::compute(chunk);
-                    let hash = u64::from_le_bytes(digest[0..8].try_into().unwrap());
+    let walker = WalkDir::new(prefix)
+        .min_depth(1)
+        .follow_links(options.follow_links)
+        .into_iter()
+        .filter_entry(|entry| {
+            let relative = entry.path().strip_prefix(prefix).unwrap_or(entry.path());
+            if filter.is_excluded(relative) {
+                return false;
+            }
+
+            if !options.ignore_files {
+                return true;
+            }
+
+            let is_dir = entry.file_type().is_dir();
+            if entry.file_name() == IGNORE_FILE && !is_dir {
+                return false;
+            }
+
+            if ignores.is_ignored(entry.path(), entry.depth(), is_dir) {
+                return false;
+            }
 
-                    file_chunk_info.chunks.push(Chunk {
-                        hash,
-                        offset: entry.offset as u64,
-                        length: entry.length as u64,
-                    });
+            if is_dir {
+                if let Err(err) = ignores.enter(entry.path(), entry.depth()) {
+                    ignore_errors.borrow_mut().push(err);
+                    return false;
                 }
+            }
 
-                manifest.lock().unwrap().files.push(file_chunk_info);
-            });
+            true
+        });
+
+    for entry in walker {
+        for err in ignore_errors.take() {
+            skip_ignore_file(&mut skipped, err)?;
         }
 
-        drop(pool);
+        let info = match entry {
+            Ok(info) => info,
+            Err(err) => {
+                let file = SkippedFile {
+                    path: err.path().unwrap_or(prefix).to_path_buf(),
+                    error: err.into(),
+                };
+
+                // There is nothing to generate if the root can not be read.
+                if file.path == prefix {
+                    return Err(file.into());
+                }
+
+                skip_or_fail(&mut skipped, file)?;
+                continue;
+            }
+        };
+
+        let name = info.file_name().to_os_string();
+        let relative = info.path().strip_prefix(prefix).unwrap_or(info.path());
+        let directory = relative.to_path_buf();
+
+        if info.file_type().is_dir() {
+            list.directories.push(FileInfo { name, directory });
+        } else if !filter.is_included(relative) {
+            continue;
+        } else if info.file_type().is_file() {
+            list.files.push(FileInfo { name, directory });
+        } else if info.file_type().is_symlink() {
+            list.links.push(FileInfo { name, directory });
+        }
+    }
 
-        let mut manifest = Arc::try_unwrap(manifest).unwrap().into_inner().unwrap();
+    for err in ignore_errors.take() {
+        skip_ignore_file(&mut skipped, err)?;
+    }
 
-        manifest.files.sort_by_cached_key(|k| k.path.clone());
+    // Directories are walked to find included files but only kept if they
+    // are included themselves or hold something that is.
+    if !filter.is_empty() {
+        let mut parents = HashSet::new();
+        for entry in list.files.iter().chain(list.links.iter()) {
+            parents.extend(entry.directory.ancestors().skip(1));
+        }
 
-        manifest
+        list.directories.retain(|entry| {
+            filter.is_included(&entry.directory) || parents.contains(entry.directory.as_path())
+        });
     }
+
+    Ok(list)
+}
+
+/// Chunks every file in the list on a thread pool and collects the results
+/// into a manifest. Unchanged files are taken from the previous manifest
+/// when there is one.
+fn generate(
+    prefix: &Path,
+    file_list: &FileList,
+    options: &ManifestOptions,
+    previous: Option<&Manifest>,
+    mut skipped: Option<&mut Vec<SkippedFile>>,
+) -> Result<Manifest, BinsyncError> {
+    let config = options.chunker;
+    config.validate()?;
+
+    let mut manifest = Manifest::new();
+    manifest.header = ManifestHeader::new(config, HashAlgorithm::Blake3);
+
+    // Chunks can only be reused if they were produced the same way.
+    let previous_files: HashMap<&Path, &FileChunkInfo> = previous
+        .filter(|previous| previous.header == manifest.header)
+        .map(|previous| {
+            previous
+                .files
+                .iter()
+                .map(|file| (file.path.as_path(), file))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut hardlinks = if options.follow_links {
+        HashMap::new()
+    } else {
+        find_hardlinks(prefix, file_list)
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let pool = ThreadPool::new(4);
+
+    for file_info in &file_list.files {
+        if hardlinks.contains_key(&file_info.directory) {
+            continue;
+        }
+
+        let key = file_info.directory.clone();
+        let path = prefix.join(&file_info.directory);
+        let previous = previous_files
+            .get(file_info.directory.as_path())
+            .map(|&file| file.clone());
+        let compare_inodes = options.compare_inodes;
+        let sender = sender.clone();
+
+        pool.execute(move || {
+            let result = match previous {
+                Some(previous) => update_file(&path, previous, config, compare_inodes),
+                None => chunk_file(&path, key, config),
+            }
+            .map_err(|error| SkippedFile { path, error });
+
+            // The receiver is only dropped once the pool is done.
+            let _ = sender.send(result);
+        });
+    }
+
+    drop(sender);
+    drop(pool);
+
+    let mut failures = Vec::new();
+    for result in receiver {
+        match result {
+            Ok(file_chunk_info) => manifest.files.push(file_chunk_info),
+            Err(file) => failures.push(file),
+        }
+    }
+
+    // Report failures in a stable order no matter which thread finished first.
+    failures.sort_by(|a, b| a.path.cmp(&b.path));
+    for file in failures {
+        skip_or_fail(&mut skipped, file)?;
+    }
+
+    manifest.files.sort_by_cached_key(|k| k.path.clone());
+
+    for directory_info in &file_list.directories {
+        manifest.directories.push(DirectoryInfo {
+            path: directory_info.directory.clone(),
+        });
+    }
+
+    manifest.directories.sort_by_cached_key(|k| k.path.clone());
+
+    for link_info in &file_list.links {
+        let path = prefix.join(&link_info.directory);
+
+        match std::fs::read_link(&path) {
+            Ok(target) => manifest.links.push(LinkInfo {
+                path: link_info.directory.clone(),
+                kind: LinkKind::Symlink(target),
+            }),
+            Err(error) => skip_or_fail(&mut skipped, SkippedFile { path, error })?,
+        }
+    }
+
+    // Hard links to a file that was skipped have nothing to point at.
+    let mut orphaned: Vec<PathBuf> = hardlinks
+        .iter()
+        .filter(|(_, primary)| {
+            manifest
+                .files
+                .binary_search_by(|f| f.path.cmp(primary))
+                .is_err()
+        })
+        .map(|(path, _)| path.clone())
+        .collect();
+    orphaned.sort();
+
+    for path in orphaned {
+        hardlinks.remove(&path);
+
+        let error = io::Error::new(io::ErrorKind::NotFound, "hard linked file was skipped");
+        let path = prefix.join(path);
+        skip_or_fail(&mut skipped, SkippedFile { path, error })?;
+    }
+
+    for (path, primary) in hardlinks {
+        manifest.links.push(LinkInfo {
+            path,
+            kind: LinkKind::Hardlink(primary),
+        });
+    }
+
+    manifest.links.sort_by_cached_key(|k| k.path.clone());
+
+    check_collisions(&manifest, options)?;
+
+    Ok(manifest)
+}
+
+/// Fails on the first pair of colliding paths unless the options allow them.
+pub(crate) fn check_collisions(
+    manifest: &Manifest,
+    options: &ManifestOptions,
+) -> Result<(), BinsyncError> {
+    if options.allow_path_collisions {
+        return Ok(());
+    }
+
+    match manifest.path_collisions().into_iter().next() {
+        Some(collision) => Err(BinsyncError::PathCollision {
+            first: collision.first,
+            second: collision.second,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Reuses the chunks of a file from a previous manifest if it has not
+/// changed since, otherwise chunks it again.
+fn update_file(
+    path: &Path,
+    previous: FileChunkInfo,
+    config: ChunkerConfig,
+    compare_inodes: bool,
+) -> io::Result<FileChunkInfo> {
+    let metadata = std::fs::metadata(path)?;
+    let current = FileMetadata::from_metadata(&metadata);
+
+    let unchanged = previous.digest.is_some()
+        && previous.length == metadata.len()
+        && previous.metadata.modified.is_some()
+        && previous.metadata.modified == current.modified
+        && (!compare_inodes || previous.metadata.inode == current.inode);
+
+    if !unchanged {
+        return chunk_file(path, previous.path, config);
+    }
+
+    // Permissions change without touching the modification time.
+    Ok(FileChunkInfo {
+        metadata: current,
+        ..previous
+    })
+}
+
+/// Streams a single file through the chunker.
+fn chunk_file(path: &Path, key: PathBuf, config: ChunkerConfig) -> io::Result<FileChunkInfo> {
+    let metadata = std::fs::metadata(path)?;
+    let file = File::open(path)?;
+
+    chunk_reader(file, key, config, FileMetadata::from_metadata(&metadata))
+}
+
+/// Chunks everything in the reader as the contents of a single file.
+pub(crate) fn chunk_reader<R: Read>(
+    reader: R,
+    key: PathBuf,
+    config: ChunkerConfig,
+    metadata: FileMetadata,
+) -> io::Result<FileChunkInfo> {
+    let buffer_size = config.max_size as usize * BUFFERED_CHUNKS;
+    let mut chunker = StreamChunker::new(reader, config, buffer_size);
+    let mut hasher = blake3::Hasher::new();
+
+    let mut file_chunk_info = FileChunkInfo {
+        path: key,
+        length: 0,
+        digest: None,
+        chunks: Vec::new(),
+        metadata,
+    };
+
+    while let Some((offset, chunk)) = chunker.next_chunk()? {
+        hasher.update(chunk);
+
+        file_chunk_info.chunks.push(Chunk {
+            hash: HashAlgorithm::Blake3.digest(chunk),
+            offset,
+            length: chunk.len() as u64,
+            zero: chunk.iter().all(|&b| b == 0),
+        });
+
+        file_chunk_info.length += chunk.len() as u64;
+    }
+
+    file_chunk_info.digest = Some(Digest(*hasher.finalize().as_bytes()));
+
+    Ok(file_chunk_info)
+}
+
+/// Groups files in the list that share the same underlying file. Returns a
+/// map of every file that should become a hard link to the file it links to.
+/// The first path of each group in sorted order is the one that gets chunked.
+#[cfg(unix)]
+fn find_hardlinks(prefix: &Path, file_list: &FileList) -> HashMap<PathBuf, PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut paths: Vec<&PathBuf> = file_list.files.iter().map(|f| &f.directory).collect();
+    paths.sort();
+
+    let mut primaries: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut hardlinks = HashMap::new();
+
+    for path in paths {
+        // Files that cannot be read are reported once they are chunked.
+        let metadata = match std::fs::metadata(prefix.join(path)) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        if metadata.nlink() < 2 {
+            continue;
+        }
+
+        match primaries.get(&(metadata.dev(), metadata.ino())) {
+            Some(primary) => {
+                hardlinks.insert(path.clone(), primary.clone());
+            }
+            None => {
+                primaries.insert((metadata.dev(), metadata.ino()), path.clone());
+            }
+        }
+    }
+
+    hardlinks
+}
+
+/// Hard links are only detected on Unix so every file gets its own entry.
+#[cfg(not(unix))]
+fn find_hardlinks(_prefix: &Path, _file_list: &FileList) -> HashMap<PathBuf, PathBuf> {
+    HashMap::new()
 }
 
 impl Default for Manifest {
@@ -1,32 +1,237 @@
+pub mod diff;
+mod filter;
+pub mod layered;
+mod legacy;
+pub mod limits;
 pub mod manifest;
+pub mod path;
 pub mod provider;
+pub mod signing;
+pub mod source;
+pub mod stats;
+mod stream;
 pub mod sync;
 
 #[cfg(feature = "network")]
 pub mod network;
 
-use std::path::PathBuf;
+use std::{collections::HashMap, ffi::OsString, fmt, io::Read, path::PathBuf, str::FromStr};
 
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::BinsyncError;
+use crate::{BinsyncError, LinkInfo};
 
-/// Constant values for the CDC chunker. The producer and consumer need to use
-/// the same values so be careful changing these.
+/// Default values for the CDC chunker. The values used to produce a manifest
+/// are recorded in its header so the consumer chunks the same way.
 const MIN_CHUNK: usize = 32768;
 const AVG_CHUNK: usize = 65536;
 const MAX_CHUNK: usize = 131072;
 
-/// ChunkId is defined for the entire crate in a single location.
-type ChunkId = u64;
+/// Content defined chunking algorithms.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ChunkingAlgorithm {
+    /// FastCDC as implemented by version 1 of the `fastcdc` crate.
+    FastCdc,
+}
+
+/// Chunk size limits for the chunker in bytes.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct ChunkerConfig {
+    pub min_size: u32,
+    pub avg_size: u32,
+    pub max_size: u32,
+}
+
+impl ChunkerConfig {
+    /// Creates a config with the given sizes in bytes, making sure the
+    /// chunker is able to use them.
+    pub fn new(min_size: u32, avg_size: u32, max_size: u32) -> Result<ChunkerConfig, BinsyncError> {
+        let config = ChunkerConfig {
+            min_size,
+            avg_size,
+            max_size,
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Checks the sizes are ordered and within the limits of the chunker.
+    pub fn validate(&self) -> Result<(), BinsyncError> {
+        let (min, avg, max) = (
+            self.min_size as usize,
+            self.avg_size as usize,
+            self.max_size as usize,
+        );
+
+        let in_range = (fastcdc::MINIMUM_MIN..=fastcdc::MINIMUM_MAX).contains(&min)
+            && (fastcdc::AVERAGE_MIN..=fastcdc::AVERAGE_MAX).contains(&avg)
+            && (fastcdc::MAXIMUM_MIN..=fastcdc::MAXIMUM_MAX).contains(&max);
+
+        if !in_range || min > avg || avg > max {
+            return Err(BinsyncError::InvalidChunkerConfig {
+                min: self.min_size,
+                avg: self.avg_size,
+                max: self.max_size,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Creates a chunker over the data using these sizes.
+    pub(crate) fn chunker<'a>(&self, data: &'a [u8]) -> fastcdc::FastCDC<'a> {
+        fastcdc::FastCDC::new(
+            data,
+            self.min_size as usize,
+            self.avg_size as usize,
+            self.max_size as usize,
+        )
+    }
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        ChunkerConfig {
+            min_size: MIN_CHUNK as u32,
+            avg_size: AVG_CHUNK as u32,
+            max_size: MAX_CHUNK as u32,
+        }
+    }
+}
+
+/// A full 256-bit digest. Used to identify chunks and whole files. Written as
+/// raw bytes in binary formats and as a hex string in human readable ones.
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
+pub struct Digest(pub [u8; 32]);
+
+impl FromStr for Digest {
+    type Err = BinsyncError;
+
+    /// Parses the hex string written by `Display`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        signing::parse_hex(text)
+            .map(Digest)
+            .ok_or_else(|| BinsyncError::InvalidDigest(text.to_string()))
+    }
+}
+
+impl Serialize for Digest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Digest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let text = String::deserialize(deserializer)?;
+            text.parse().map_err(de::Error::custom)
+        } else {
+            Ok(Digest(<[u8; 32]>::deserialize(deserializer)?))
+        }
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Digest({})", self)
+    }
+}
+
+/// ChunkId is defined for the entire crate in a single location. It holds a
+/// full digest of the chunk contents so that two different chunks never end
+/// up sharing an id.
+pub type ChunkId = Digest;
+
+/// Hash functions used to produce chunk ids.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum HashAlgorithm {
+    /// The first 8 bytes of an MD5 digest padded with zeros. Only manifests
+    /// from before chunk ids were widened use this.
+    Md5Truncated,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Computes the chunk id of the given data.
+    pub fn digest(&self, data: &[u8]) -> Digest {
+        let mut id = [0; 32];
+
+        match self {
+            HashAlgorithm::Md5Truncated => id[..8].copy_from_slice(&md5::compute(data)[..8]),
+            HashAlgorithm::Blake3 => id = *blake3::hash(data).as_bytes(),
+        }
+
+        Digest(id)
+    }
+
+    /// Computes the digest of everything in the reader along with its length.
+    pub fn digest_reader<R: Read>(&self, mut reader: R) -> std::io::Result<(Digest, u64)> {
+        let mut buffer = vec![0; 65536];
+        let mut length = 0;
+
+        let mut id = [0; 32];
+
+        match self {
+            HashAlgorithm::Md5Truncated => {
+                let mut context = md5::Context::new();
+                loop {
+                    let read = reader.read(&mut buffer)?;
+                    if read == 0 {
+                        break;
+                    }
+
+                    context.consume(&buffer[..read]);
+                    length += read as u64;
+                }
+
+                id[..8].copy_from_slice(&context.compute()[..8]);
+            }
+            HashAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                loop {
+                    let read = reader.read(&mut buffer)?;
+                    if read == 0 {
+                        break;
+                    }
+
+                    hasher.update(&buffer[..read]);
+                    length += read as u64;
+                }
+
+                id = *hasher.finalize().as_bytes();
+            }
+        }
+
+        Ok((Digest(id), length))
+    }
+}
 
 /// The most basic building block. Holds the precomputed hash identifier along
 /// with the offset in the file and length of the chunk.
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct Chunk {
     pub hash: ChunkId,
     pub offset: u64,
     pub length: u64,
+    /// The chunk only holds zero bytes. It is never fetched or written, the
+    /// syncer leaves a hole in the file instead.
+    pub zero: bool,
 }
 
 impl PartialEq<fastcdc::Chunk> for Chunk {
@@ -41,17 +246,21 @@ impl PartialEq<Chunk> for fastcdc::Chunk {
     }
 }
 
-/// Name and directory of a single file.
+/// Name and path relative to the root of a single file.
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct FileInfo {
-    pub name: String,
-    pub directory: String,
+    #[serde(with = "path::portable")]
+    pub name: OsString,
+    #[serde(with = "path::portable")]
+    pub directory: PathBuf,
 }
 
-/// List of files from a given root.
+/// List of files, directories and symbolic links from a given root.
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct FileList {
     pub files: Vec<FileInfo>,
+    pub directories: Vec<FileInfo>,
+    pub links: Vec<FileInfo>,
 }
 
 /// This describes the operations we need to take in order to transform the
@@ -64,6 +273,21 @@ pub struct SyncPlan {
 
     /// Total number of operations in the plan.
     pub total_ops: u32,
+
+    /// Directories from the manifest that are missing in the destination.
+    pub directories: Vec<PathBuf>,
+
+    /// Links that need to be created once all the files are written.
+    pub links: Vec<LinkInfo>,
+
+    /// Files and directories in the destination that are not part of the
+    /// manifest. Directories always come after their contents.
+    pub deletions: Vec<PathBuf>,
+
+    /// The layer every path in the manifest comes from when syncing a
+    /// `LayeredManifest`. Empty otherwise. Paths owned by a held layer are
+    /// kept but left out of the rest of the plan.
+    pub owners: HashMap<PathBuf, String>,
 }
 
 impl SyncPlan {
@@ -74,7 +298,7 @@ impl SyncPlan {
         for (_, operations) in &self.operations {
             for operation in operations {
                 if let Operation::Fetch(chunk) = operation {
-                    size = size + chunk.length;
+                    size += chunk.length;
                 }
             }
         }
@@ -83,11 +307,25 @@ impl SyncPlan {
     }
 }
 
+/// Decides what happens to files in the destination that the manifest does
+/// not list.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub enum DeletePolicy {
+    /// Leave extra files where they are.
+    #[default]
+    Keep,
+    /// Remove extra files along with any directories left empty.
+    Delete,
+    /// Move extra files into the given folder keeping their relative path.
+    Quarantine(PathBuf),
+}
+
 /// A single operation in a sync plan.
 pub enum Operation {
     Seek(i64), // Since seek can go both ways it uses a signed int.
     Copy(Chunk),
     Fetch(Chunk),
+    Hole(u64), // Zeros that are left sparse instead of being written.
 }
 
 /// Trait for providing chunks. This allows you to customize the implementation
@@ -100,5 +338,5 @@ pub trait ChunkProvider {
 
     /// Gets the raw data of the chunk. The provider may choose to modify its
     /// internal cache when fetching a chunk.
-    fn get_chunk<'a>(&'a mut self, key: &u64) -> Result<&'a [u8], BinsyncError>;
+    fn get_chunk<'a>(&'a mut self, key: &ChunkId) -> Result<&'a [u8], BinsyncError>;
 }
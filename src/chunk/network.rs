@@ -1,6 +1,7 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::TryInto,
+    io::Read,
     sync::{
         mpsc::{self, Receiver},
         Arc,
@@ -9,12 +10,20 @@ use std::{
 
 use serde::{Deserialize, Serialize};
 
-use crate::{sync::ThreadPool, BinsyncError, ChunkProvider, Manifest};
+use ed25519_dalek::SigningKey;
 
-use super::ChunkId;
+use crate::{sync::ThreadPool, BinsyncError, ChunkProvider, Manifest, ManifestLimits, TrustedKeys};
 
-/// ID type for packs defined in a single location.
-type PackId = u64;
+use super::{
+    legacy::LegacyRemoteManifest,
+    limits,
+    manifest::{self, ManifestHeader},
+    Chunk, ChunkId, Digest, HashAlgorithm,
+};
+
+/// ID type for packs defined in a single location. Like chunk ids it is a
+/// full digest, computed over the ids of the chunks in the pack.
+type PackId = Digest;
 
 const DEFAULT_PACK_SIZE: usize = 4194304; // 4MB
 
@@ -26,6 +35,47 @@ pub struct Pack {
     pub chunks: Vec<ChunkId>,
 }
 
+impl Pack {
+    /// The pack id is a digest over the ids of its chunks using the hash
+    /// algorithm of the manifest.
+    fn new(length: u64, chunks: Vec<ChunkId>, algorithm: HashAlgorithm) -> Pack {
+        let mut bytes = Vec::with_capacity(chunks.len() * 32);
+        for chunk_id in &chunks {
+            bytes.extend_from_slice(&chunk_id.0);
+        }
+
+        Pack {
+            hash: algorithm.digest(&bytes),
+            length,
+            chunks,
+        }
+    }
+}
+
+/// Name of the file a pack is served from. Packs of converted manifests keep
+/// the decimal names they were published under before ids were widened.
+fn pack_file_name(pack_id: &PackId, algorithm: HashAlgorithm) -> String {
+    match algorithm {
+        HashAlgorithm::Md5Truncated => {
+            let id = u64::from_le_bytes(pack_id.0[..8].try_into().unwrap());
+            format!("{}.binpack", id)
+        }
+        HashAlgorithm::Blake3 => format!("{}.binpack", pack_id),
+    }
+}
+
+/// The chunks that go into packs in order. Zero chunks are never fetched and
+/// chunks seen before are already in a pack, so both are left out.
+fn packed_chunks(manifest: &Manifest) -> impl Iterator<Item = &Chunk> {
+    let mut seen = HashSet::new();
+
+    manifest
+        .files
+        .iter()
+        .flat_map(|f| f.chunks.iter())
+        .filter(move |c| !c.zero && seen.insert(c.hash))
+}
+
 /// Wraps a chunk manifest so that chunks can be logically grouped into packs.
 /// Packs reduce the amount of requests needed to sync across a remote pipe.
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -45,47 +95,31 @@ impl RemoteManifest {
     /// up to the limit without going over.
     pub fn with_pack_size(size: usize, manifest: Manifest) -> RemoteManifest {
         let mut packs = Vec::new();
+        let algorithm = manifest.header.hash;
 
         let mut length = 0;
-        let mut bytes: Vec<u8> = Vec::new();
         let mut chunks: Vec<ChunkId> = Vec::new();
 
-        for file_chunk_info in &manifest.files {
-            for chunk in &file_chunk_info.chunks {
-                // If we do not have space save off a new pack.
-                if length + chunk.length > size as u64 {
-                    let digest = md5::compute(bytes);
-                    let hash = u64::from_le_bytes(digest[0..8].try_into().unwrap());
-                    packs.push(Pack {
-                        hash,
-                        length,
-                        chunks,
-                    });
-
-                    length = 0;
-                    bytes = Vec::new();
-                    chunks = Vec::new();
-                }
+        let data_chunks = packed_chunks(&manifest);
 
-                // Add this chunk to the current pack hash bytes and chunk
-                // offset map.
-                bytes.append(&mut chunk.hash.to_le_bytes().to_vec());
-                chunks.push(chunk.hash);
+        for chunk in data_chunks {
+            // If we do not have space save off a new pack.
+            if length + chunk.length > size as u64 {
+                packs.push(Pack::new(length, chunks, algorithm));
 
-                // Increment our offset.
-                length += chunk.length;
+                length = 0;
+                chunks = Vec::new();
             }
+
+            chunks.push(chunk.hash);
+
+            // Increment our offset.
+            length += chunk.length;
         }
 
         // If we still have a partial pack save it off.
         if length > 0 {
-            let digest = md5::compute(bytes);
-            let hash = u64::from_le_bytes(digest[0..8].try_into().unwrap());
-            packs.push(Pack {
-                hash,
-                length,
-                chunks,
-            });
+            packs.push(Pack::new(length, chunks, algorithm));
         }
 
         RemoteManifest {
@@ -93,6 +127,174 @@ impl RemoteManifest {
             packs,
         }
     }
+
+    /// Signs the wrapped manifest. Packs are not signed themselves but are
+    /// checked against the signed manifest by `verify_signature`.
+    pub fn sign(&mut self, key: &SigningKey) -> Result<(), BinsyncError> {
+        self.source.sign(key)
+    }
+
+    /// Checks the wrapped manifest was signed by one of the trusted keys and
+    /// that the packs hold exactly its chunks that are not zero, in order.
+    pub fn verify_signature(&self, keys: &TrustedKeys) -> Result<(), BinsyncError> {
+        self.source.verify_signature(keys)?;
+
+        let mut chunks = packed_chunks(&self.source);
+
+        for pack in &self.packs {
+            let mut length = 0;
+
+            for chunk_id in &pack.chunks {
+                match chunks.next() {
+                    Some(chunk) if chunk.hash == *chunk_id => length += chunk.length,
+                    _ => return Err(BinsyncError::InvalidSignature),
+                }
+            }
+
+            if *pack != Pack::new(length, pack.chunks.clone(), self.source.header.hash) {
+                return Err(BinsyncError::InvalidSignature);
+            }
+        }
+
+        match chunks.next() {
+            Some(_) => Err(BinsyncError::InvalidSignature),
+            None => Ok(()),
+        }
+    }
+
+    /// Name of the file the pack should be served from, relative to the base
+    /// URL given to `RemoteChunkProvider`.
+    pub fn pack_file_name(&self, pack: &Pack) -> String {
+        pack_file_name(&pack.hash, self.source.header.hash)
+    }
+
+    /// The header of the wrapped manifest. It is also the first thing in the
+    /// serialized remote manifest.
+    pub fn header(&self) -> &ManifestHeader {
+        &self.source.header
+    }
+
+    /// Reads a remote manifest serialized with `to_bytes` or
+    /// `to_compressed_bytes`. Remote manifests written before the header
+    /// existed are converted to the current layout. The default
+    /// `ManifestLimits` apply.
+    pub fn from_bytes(data: &[u8]) -> Result<RemoteManifest, BinsyncError> {
+        RemoteManifest::from_bytes_with_limits(data, &ManifestLimits::default())
+    }
+
+    /// Similar to from_bytes with custom limits. The packs are also checked
+    /// against the chunks of the wrapped manifest.
+    pub fn from_bytes_with_limits(
+        data: &[u8],
+        limits: &ManifestLimits,
+    ) -> Result<RemoteManifest, BinsyncError> {
+        let data = limits.decompress(data)?;
+
+        let manifest = match ManifestHeader::peek(&data)? {
+            Some(_) => limits.deserialize::<RemoteManifest>(&data)?,
+            None => limits.deserialize::<LegacyRemoteManifest>(&data)?.into(),
+        };
+        limits.check(&manifest.source)?;
+        manifest.check_packs(limits)?;
+
+        Ok(manifest)
+    }
+
+    /// Makes sure every pack only holds chunks of the wrapped manifest, each
+    /// of them once, and that its length is the sum of theirs and within the
+    /// limits, since the length decides how much of a download is read.
+    /// Converted manifests were packed one chunk per occurrence, so their
+    /// packs may repeat chunks within the limits.
+    fn check_packs(&self, limits: &ManifestLimits) -> Result<(), BinsyncError> {
+        let converted = self.source.header.hash == HashAlgorithm::Md5Truncated;
+
+        let lengths: HashMap<&ChunkId, u64> = self
+            .source
+            .files
+            .iter()
+            .flat_map(|f| f.chunks.iter().map(|c| (&c.hash, c.length)))
+            .collect();
+
+        for pack in &self.packs {
+            if pack.chunks.len() > limits.max_pack_chunks || pack.length > limits.max_pack_size {
+                return Err(limits::invalid(format!(
+                    "pack {} is larger than the limit",
+                    pack.hash
+                )));
+            }
+
+            let mut length: u64 = 0;
+            let mut seen = HashSet::new();
+
+            for chunk_id in &pack.chunks {
+                if !seen.insert(chunk_id) && !converted {
+                    return Err(limits::invalid(format!(
+                        "pack {} holds chunk {} more than once",
+                        pack.hash, chunk_id
+                    )));
+                }
+
+                let chunk_length = lengths
+                    .get(chunk_id)
+                    .ok_or(BinsyncError::ChunkNotFound(*chunk_id))?;
+                length = length.saturating_add(*chunk_length);
+            }
+
+            if length != pack.length {
+                return Err(limits::invalid(format!(
+                    "pack {} does not add up to its length",
+                    pack.hash
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Serializes the remote manifest into the format read by `from_bytes`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BinsyncError> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// Serializes the remote manifest like `to_bytes` and compresses it so
+    /// clients download less before they can start syncing.
+    pub fn to_compressed_bytes(&self) -> Result<Vec<u8>, BinsyncError> {
+        manifest::compress(&self.to_bytes()?)
+    }
+
+    /// Reads a remote manifest written by `to_json`.
+    pub fn from_json(json: &str) -> Result<RemoteManifest, BinsyncError> {
+        let manifest: RemoteManifest = manifest::from_json(json, "/source/header/version")?;
+        manifest.header().validate()?;
+        let limits = ManifestLimits::default();
+        limits.check(&manifest.source)?;
+        manifest.check_packs(&limits)?;
+
+        Ok(manifest)
+    }
+
+    /// Serializes the remote manifest as pretty printed JSON.
+    pub fn to_json(&self) -> Result<String, BinsyncError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Reads a remote manifest written by `to_cbor`.
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(data: &[u8]) -> Result<RemoteManifest, BinsyncError> {
+        let manifest: RemoteManifest = manifest::from_cbor(data)?;
+        manifest.header().validate()?;
+        let limits = ManifestLimits::default();
+        limits.check(&manifest.source)?;
+        manifest.check_packs(&limits)?;
+
+        Ok(manifest)
+    }
+
+    /// Serializes the remote manifest as CBOR.
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Result<Vec<u8>, BinsyncError> {
+        manifest::to_cbor(self)
+    }
 }
 
 /// Runs download operations on a background thread.
@@ -121,9 +323,11 @@ impl AsyncDownloader {
         }
     }
 
-    pub fn download_pack(&self, pack_id: PackId) -> Receiver<Option<Vec<u8>>> {
+    /// Downloads a pack, reading at most one byte more than its length so a
+    /// mismatch is noticed without holding an arbitrarily large response.
+    pub fn download_pack(&self, file_name: &str, length: u64) -> Receiver<Option<Vec<u8>>> {
         let (sender, receiver) = mpsc::channel();
-        let url = format!("{}{}.binpack", self.base_url, pack_id);
+        let url = format!("{}{}", self.base_url, file_name);
         let client = Arc::clone(&self.client);
 
         self.pool.execute(move || {
@@ -145,14 +349,14 @@ impl AsyncDownloader {
                 return;
             }
 
-            match response.bytes() {
-                Ok(data) => sender.send(Some(data.to_vec())).unwrap(),
+            let mut data = Vec::new();
+            match response.take(length + 1).read_to_end(&mut data) {
+                Ok(_) => sender.send(Some(data)).unwrap(),
                 Err(_) => {
                     println!("Failed to get data.");
                     sender.send(None).unwrap();
-                    return;
                 }
-            };
+            }
         });
 
         receiver
@@ -179,13 +383,30 @@ pub struct RemoteChunkProvider {
     chunk_cache: HashMap<ChunkId, Vec<u8>>,
     downloader: AsyncDownloader,
     chunk_map: HashMap<ChunkId, ChunkPackInfo>,
+    algorithm: HashAlgorithm,
 }
 
 impl RemoteChunkProvider {
+    /// Similar to new but only accepts a manifest signed by one of the
+    /// trusted keys. Use the same keys with `Syncer::trusted_keys`.
+    pub fn with_trusted_keys(
+        base_url: &str,
+        manifest: &RemoteManifest,
+        keys: &TrustedKeys,
+    ) -> Result<RemoteChunkProvider, BinsyncError> {
+        manifest.verify_signature(keys)?;
+
+        RemoteChunkProvider::new(base_url, manifest)
+    }
+
     pub fn new(
         base_url: &str,
         manifest: &RemoteManifest,
     ) -> Result<RemoteChunkProvider, BinsyncError> {
+        // Manifests built in memory never went through a loader.
+        limits::check_chunks(&manifest.source)?;
+        manifest.check_packs(&ManifestLimits::default())?;
+
         let mut chunk_map = HashMap::new();
 
         // Build a local map of chunk_id => chunk for use in the next step.
@@ -204,7 +425,7 @@ impl RemoteChunkProvider {
                 match chunks.get(chunk_id) {
                     Some(chunk) => {
                         chunk_map.insert(
-                            chunk_id.clone(),
+                            *chunk_id,
                             ChunkPackInfo {
                                 pack_id: pack.hash,
                                 pack_length: pack.length,
@@ -215,7 +436,7 @@ impl RemoteChunkProvider {
 
                         offset += chunk.length;
                     }
-                    None => return Err(BinsyncError::ChunkNotFound(chunk_id.clone())),
+                    None => return Err(BinsyncError::ChunkNotFound(*chunk_id)),
                 }
             }
         }
@@ -224,6 +445,7 @@ impl RemoteChunkProvider {
             chunk_cache: HashMap::new(),
             downloader: AsyncDownloader::new(base_url),
             chunk_map,
+            algorithm: manifest.source.header.hash,
         })
     }
 }
@@ -233,21 +455,27 @@ impl ChunkProvider for RemoteChunkProvider {
         // TODO: Start fetching content, reference count chunks
     }
 
-    fn get_chunk<'a>(&'a mut self, key: &u64) -> Result<&'a [u8], BinsyncError> {
+    fn get_chunk<'a>(&'a mut self, key: &ChunkId) -> Result<&'a [u8], BinsyncError> {
         // If we already have it, return it.
-        if self.chunk_cache.contains_key(&key) {
-            return Ok(self.chunk_cache.get(&key).unwrap().as_slice());
+        if self.chunk_cache.contains_key(key) {
+            return Ok(self.chunk_cache.get(key).unwrap().as_slice());
         }
 
         // If not, download the pack and cache the chunks.
-        let pack = self.chunk_map.get(&key);
+        let pack = self.chunk_map.get(key);
 
         if pack.is_none() {
             return Err(BinsyncError::Unspecified(String::from("Pack not found!")));
         }
 
         let pack = pack.unwrap();
-        match self.downloader.download_pack(pack.pack_id).recv().unwrap() {
+        let file_name = pack_file_name(&pack.pack_id, self.algorithm);
+        match self
+            .downloader
+            .download_pack(&file_name, pack.pack_length)
+            .recv()
+            .unwrap()
+        {
             Some(data) => {
                 if data.len() != pack.pack_length as usize {
                     return Err(BinsyncError::Unspecified(String::from(
@@ -255,7 +483,8 @@ impl ChunkProvider for RemoteChunkProvider {
                     )));
                 }
 
-                // Cache all the chunks from this pack
+                // Cache all the chunks from this pack. Only the manifest is
+                // signed so every chunk is checked against its id first.
                 for chunk in &self.chunk_map {
                     let chunk_info = chunk.1;
                     if chunk_info.pack_id == pack.pack_id {
@@ -263,8 +492,12 @@ impl ChunkProvider for RemoteChunkProvider {
                         let end = (chunk_info.offset + chunk_info.length) as usize;
 
                         if data.len() >= end {
-                            self.chunk_cache
-                                .insert(chunk.0.clone(), data[start..end].to_vec());
+                            let chunk_data = &data[start..end];
+                            if self.algorithm.digest(chunk_data) != *chunk.0 {
+                                return Err(BinsyncError::CorruptChunk(*chunk.0));
+                            }
+
+                            self.chunk_cache.insert(*chunk.0, chunk_data.to_vec());
                         }
                     }
                 }
@@ -0,0 +1,306 @@
+//! Paths are stored in manifests as `/` separated UTF-8 text no matter which
+//! platform generated them. Anything that would not survive that, like bytes
+//! that are not valid UTF-8, `%` and either separator inside a name, is
+//! written as `%XX` so every name on disk round trips exactly.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::{OsStr, OsString},
+    path::{Component, Path, PathBuf, MAIN_SEPARATOR},
+};
+
+use serde::{de, Deserialize, Deserializer, Serializer};
+use unicode_normalization::UnicodeNormalization;
+
+use super::manifest::Manifest;
+
+/// Most symbolic links followed while resolving a single link, the same
+/// limit Linux uses. Links that loop fail the check.
+const MAX_LINK_HOPS: usize = 40;
+
+/// Why two paths in a manifest would end up as the same file on some file
+/// systems.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CollisionKind {
+    /// The paths only differ by case, as on Windows and macOS by default.
+    Case,
+    /// The paths are the same text in a different Unicode normalization form,
+    /// which macOS treats as equal.
+    Normalization,
+}
+
+/// Two paths in a manifest that a case-insensitive or normalizing file system
+/// would store in the same place.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct PathCollision {
+    pub first: PathBuf,
+    pub second: PathBuf,
+    pub kind: CollisionKind,
+}
+
+impl Manifest {
+    /// Finds files, directories and links whose paths collide on common case
+    /// insensitive or Unicode normalizing file systems. Paths that are not
+    /// valid Unicode are never reported.
+    pub fn path_collisions(&self) -> Vec<PathCollision> {
+        let mut paths: Vec<&Path> = self
+            .files
+            .iter()
+            .map(|f| f.path.as_path())
+            .chain(self.directories.iter().map(|d| d.path.as_path()))
+            .chain(self.links.iter().map(|l| l.path.as_path()))
+            .collect();
+        paths.sort();
+
+        let mut seen: HashMap<String, &Path> = HashMap::new();
+        let mut collisions = Vec::new();
+
+        for path in paths {
+            let text = match path.to_str() {
+                Some(text) => text,
+                None => continue,
+            };
+
+            let normalized: String = text.nfc().collect();
+            let key = normalized.to_lowercase();
+
+            match seen.get(&key) {
+                Some(first) => {
+                    let first_normalized: Option<String> =
+                        first.to_str().map(|text| text.nfc().collect());
+
+                    let kind = if first_normalized.as_ref() == Some(&normalized) {
+                        CollisionKind::Normalization
+                    } else {
+                        CollisionKind::Case
+                    };
+
+                    collisions.push(PathCollision {
+                        first: first.to_path_buf(),
+                        second: path.to_path_buf(),
+                        kind,
+                    });
+                }
+                None => {
+                    seen.insert(key, path);
+                }
+            }
+        }
+
+        collisions
+    }
+}
+
+/// Whether a path from a manifest names something inside the root it is
+/// joined to. Only plain names are allowed, so `..`, `.`, roots and Windows
+/// prefixes are all rejected.
+pub(crate) fn is_contained(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Whether a symbolic link at `link` pointing at `target` resolves to
+/// something inside the root. The other symbolic links of the manifest,
+/// given as link paths and their targets, are followed along the way since a
+/// `..` after one of them leads up from wherever it points.
+pub(crate) fn link_is_contained(link: &Path, target: &Path, links: &HashMap<&Path, &Path>) -> bool {
+    let mut pending: VecDeque<Component> = link
+        .parent()
+        .unwrap_or(Path::new(""))
+        .components()
+        .collect();
+    pending.extend(target.components());
+
+    let mut resolved = PathBuf::new();
+    let mut hops = 0;
+
+    while let Some(component) = pending.pop_front() {
+        match component {
+            Component::Normal(name) => {
+                resolved.push(name);
+
+                if let Some(next) = links.get(resolved.as_path()) {
+                    hops += 1;
+                    if hops > MAX_LINK_HOPS {
+                        return false;
+                    }
+
+                    resolved.pop();
+                    for component in next.components().rev() {
+                        pending.push_front(component);
+                    }
+                }
+            }
+            Component::CurDir => {}
+            Component::ParentDir if resolved.pop() => {}
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+/// Writes a path in the portable form. `.` components in the middle and
+/// trailing separators are dropped like `Path::components` does.
+pub(crate) fn encode(path: &Path) -> String {
+    let mut text = String::new();
+    let mut separate = false;
+
+    for component in path.components() {
+        if separate {
+            text.push('/');
+        }
+        separate = true;
+
+        match component {
+            Component::RootDir => {
+                text.push('/');
+                separate = false;
+            }
+            Component::CurDir => text.push('.'),
+            Component::ParentDir => text.push_str(".."),
+            Component::Prefix(prefix) => escape(prefix.as_os_str(), &mut text),
+            Component::Normal(name) => escape(name, &mut text),
+        }
+    }
+
+    text
+}
+
+/// Reads a path written by `encode` into the native form of this platform.
+pub(crate) fn decode(text: &str) -> Option<PathBuf> {
+    let mut path = OsString::new();
+
+    if text.starts_with('/') {
+        path.push(MAIN_SEPARATOR.to_string());
+    }
+
+    let mut separate = false;
+    for name in text.split('/').filter(|name| !name.is_empty()) {
+        if separate {
+            path.push(MAIN_SEPARATOR.to_string());
+        }
+        separate = true;
+
+        path.push(unescape(name)?);
+    }
+
+    Some(PathBuf::from(path))
+}
+
+fn escape(name: &OsStr, text: &mut String) {
+    for chunk in name.as_encoded_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '%' | '/' | '\\' => text.push_str(&format!("%{:02X}", c as u8)),
+                c => text.push(c),
+            }
+        }
+
+        for byte in chunk.invalid() {
+            text.push_str(&format!("%{:02X}", byte));
+        }
+    }
+}
+
+fn unescape(name: &str) -> Option<OsString> {
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte != b'%' {
+            bytes.push(byte);
+            rest = tail;
+            continue;
+        }
+
+        let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        rest = &tail[2..];
+    }
+
+    native(bytes)
+}
+
+#[cfg(unix)]
+fn native(bytes: Vec<u8>) -> Option<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+
+    Some(OsString::from_vec(bytes))
+}
+
+/// Names on Windows are UTF-16 that may hold unpaired surrogates, which are
+/// encoded the way WTF-8 does.
+#[cfg(windows)]
+fn native(bytes: Vec<u8>) -> Option<OsString> {
+    use std::os::windows::ffi::OsStringExt;
+
+    let bytes = match String::from_utf8(bytes) {
+        Ok(text) => return Some(OsString::from(text)),
+        Err(err) => err.into_bytes(),
+    };
+
+    let mut wide = Vec::with_capacity(bytes.len());
+    let mut rest = bytes.as_slice();
+
+    while let Some(&lead) = rest.first() {
+        let (length, min, mut code) = match lead {
+            0x00..=0x7F => (1, 0, lead as u32),
+            0xC0..=0xDF => (2, 0x80, lead as u32 & 0x1F),
+            0xE0..=0xEF => (3, 0x800, lead as u32 & 0x0F),
+            0xF0..=0xF4 => (4, 0x10000, lead as u32 & 0x07),
+            _ => return None,
+        };
+
+        for &byte in rest.get(1..length)? {
+            if byte & 0xC0 != 0x80 {
+                return None;
+            }
+            code = (code << 6) | (byte as u32 & 0x3F);
+        }
+
+        if code < min || code > 0x10FFFF {
+            return None;
+        }
+
+        if code >= 0x10000 {
+            code -= 0x10000;
+            wide.push(0xD800 | (code >> 10) as u16);
+            wide.push(0xDC00 | (code & 0x3FF) as u16);
+        } else {
+            wide.push(code as u16);
+        }
+
+        rest = &rest[length..];
+    }
+
+    Some(OsString::from_wide(&wide))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn native(bytes: Vec<u8>) -> Option<OsString> {
+    String::from_utf8(bytes).ok().map(OsString::from)
+}
+
+/// Serializes paths and names in the portable form. Used with
+/// `#[serde(with = "portable")]` on every path a manifest stores.
+pub(crate) mod portable {
+    use super::*;
+
+    pub fn serialize<S: Serializer, P: AsRef<Path>>(
+        path: &P,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode(path.as_ref()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, P: From<PathBuf>>(
+        deserializer: D,
+    ) -> Result<P, D::Error> {
+        let text = String::deserialize(deserializer)?;
+
+        decode(&text)
+            .map(P::from)
+            .ok_or_else(|| de::Error::custom(format!("invalid path {}", text)))
+    }
+}
@@ -5,6 +5,8 @@ use std::{
     path::{Path, PathBuf},
 };
 
+#[cfg(feature = "tar")]
+use super::source::tar_path;
 use super::{ChunkId, ChunkProvider, Operation, SyncPlan};
 
 use crate::BinsyncError;
@@ -22,6 +24,7 @@ struct ProviderChunk {
 /// are used more than once.
 pub struct CachingChunkProvider {
     source: PathBuf,
+    layer_sources: HashMap<String, PathBuf>,
     chunks: HashMap<ChunkId, ProviderChunk>,
     empty_chunk: Option<ChunkId>,
 }
@@ -30,26 +33,41 @@ impl CachingChunkProvider {
     pub fn new<P: AsRef<Path>>(path: P) -> CachingChunkProvider {
         CachingChunkProvider {
             source: PathBuf::from(path.as_ref()),
+            layer_sources: HashMap::new(),
             chunks: HashMap::new(),
             empty_chunk: None,
         }
     }
+
+    /// Reads files owned by the named layer of a `LayeredManifest` from a
+    /// different source directory. Other files are read from the path the
+    /// provider was created with.
+    pub fn layer_source<S: Into<String>, P: AsRef<Path>>(&mut self, layer: S, path: P) {
+        self.layer_sources
+            .insert(layer.into(), path.as_ref().to_path_buf());
+    }
 }
 
 impl ChunkProvider for CachingChunkProvider {
     fn set_plan(&mut self, plan: &SyncPlan) {
         for (file_path, operations) in &plan.operations {
+            let source = plan
+                .owners
+                .get(file_path)
+                .and_then(|layer| self.layer_sources.get(layer))
+                .unwrap_or(&self.source);
+
             for operation in operations {
                 if let Operation::Fetch(chunk) = operation {
                     match self.chunks.get_mut(&chunk.hash) {
                         Some(provider_chunk) => {
-                            provider_chunk.ref_count = provider_chunk.ref_count + 1;
+                            provider_chunk.ref_count += 1;
                         }
                         None => {
                             self.chunks.insert(
                                 chunk.hash,
                                 ProviderChunk {
-                                    file: self.source.join(Path::new(&file_path)),
+                                    file: source.join(Path::new(&file_path)),
                                     offset: chunk.offset,
                                     length: chunk.length,
                                     ref_count: 1,
@@ -63,21 +81,21 @@ impl ChunkProvider for CachingChunkProvider {
         }
     }
 
-    fn get_chunk<'a>(&'a mut self, key: &u64) -> Result<&'a [u8], BinsyncError> {
+    fn get_chunk<'a>(&'a mut self, key: &ChunkId) -> Result<&'a [u8], BinsyncError> {
         if let Some(chunk_id) = self.empty_chunk {
             self.chunks.remove(&chunk_id);
         }
 
         if let Some(chunk) = self.chunks.get_mut(key) {
-            chunk.ref_count = chunk.ref_count - 1;
+            chunk.ref_count -= 1;
 
             // If this is no longer needed set it for deletion.
             if chunk.ref_count == 0 {
-                self.empty_chunk = Some(key.clone());
+                self.empty_chunk = Some(*key);
             }
 
             // First check the cache.
-            if let None = chunk.data {
+            if chunk.data.is_none() {
                 // Not in the cache so lets read it.
                 let mut file = File::open(&chunk.file).map_err(|_| BinsyncError::AccessDenied)?;
                 let mut buffer = vec![0; chunk.length as usize];
@@ -97,6 +115,95 @@ impl ChunkProvider for CachingChunkProvider {
         }
 
         // Not sure why this is requesting a chunk not in the plan.
-        Err(BinsyncError::ChunkNotFound(key.clone()))
+        Err(BinsyncError::ChunkNotFound(*key))
+    }
+}
+
+/// A chunk provider reading chunks straight out of the tar archive a manifest
+/// was generated from with `TarSource`. The archive is indexed once when the
+/// provider is created and must not be compressed, since chunks are read by
+/// seeking to them.
+#[cfg(feature = "tar")]
+pub struct TarChunkProvider {
+    file: File,
+    entries: HashMap<PathBuf, u64>,
+    chunks: HashMap<ChunkId, (u64, u64)>,
+    buffer: Vec<u8>,
+}
+
+#[cfg(feature = "tar")]
+impl TarChunkProvider {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<TarChunkProvider, BinsyncError> {
+        use tar::EntryType;
+
+        let path = path.as_ref();
+        let read_failed = |source| BinsyncError::ReadFailed {
+            path: path.to_path_buf(),
+            source,
+        };
+
+        let file = File::open(path).map_err(read_failed)?;
+        let mut archive = tar::Archive::new(&file);
+        let mut entries = HashMap::new();
+
+        // Later entries replace earlier ones, matching `TarSource`.
+        for entry in archive.entries().map_err(read_failed)? {
+            let entry = entry.map_err(read_failed)?;
+
+            if !matches!(
+                entry.header().entry_type(),
+                EntryType::Regular | EntryType::Continuous
+            ) {
+                continue;
+            }
+
+            if let Some(entry_path) = tar_path(&entry.path().map_err(read_failed)?) {
+                entries.insert(entry_path, entry.raw_file_position());
+            }
+        }
+
+        Ok(TarChunkProvider {
+            file,
+            entries,
+            chunks: HashMap::new(),
+            buffer: Vec::new(),
+        })
+    }
+}
+
+#[cfg(feature = "tar")]
+impl ChunkProvider for TarChunkProvider {
+    fn set_plan(&mut self, plan: &SyncPlan) {
+        for (file_path, operations) in &plan.operations {
+            let start = match self.entries.get(file_path) {
+                Some(start) => *start,
+                None => continue,
+            };
+
+            for operation in operations {
+                if let Operation::Fetch(chunk) = operation {
+                    self.chunks
+                        .entry(chunk.hash)
+                        .or_insert((start + chunk.offset, chunk.length));
+                }
+            }
+        }
+    }
+
+    fn get_chunk<'a>(&'a mut self, key: &ChunkId) -> Result<&'a [u8], BinsyncError> {
+        let (offset, length) = *self
+            .chunks
+            .get(key)
+            .ok_or(BinsyncError::ChunkNotFound(*key))?;
+
+        self.buffer.resize(length as usize, 0);
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .map_err(|_| BinsyncError::AccessDenied)?;
+        self.file
+            .read_exact(&mut self.buffer)
+            .map_err(|_| BinsyncError::AccessDenied)?;
+
+        Ok(&self.buffer)
     }
 }
@@ -0,0 +1,154 @@
+use std::fmt;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    ser::SerializeTuple,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::BinsyncError;
+
+/// An Ed25519 signature over a manifest and the public key that made it.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct ManifestSignature {
+    #[serde(with = "fixed_bytes")]
+    pub key: [u8; 32],
+    #[serde(with = "fixed_bytes")]
+    pub signature: [u8; 64],
+}
+
+impl ManifestSignature {
+    pub(crate) fn sign(key: &SigningKey, data: &[u8]) -> ManifestSignature {
+        ManifestSignature {
+            key: key.verifying_key().to_bytes(),
+            signature: key.sign(data).to_bytes(),
+        }
+    }
+}
+
+/// The public keys a manifest signature is checked against. A manifest is
+/// only accepted if it was signed by one of them.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedKeys {
+    keys: Vec<VerifyingKey>,
+}
+
+impl TrustedKeys {
+    pub fn new() -> TrustedKeys {
+        TrustedKeys::default()
+    }
+
+    /// Trusts manifests signed by the given public key.
+    pub fn add(&mut self, key: VerifyingKey) {
+        self.keys.push(key);
+    }
+
+    /// Trusts a public key written as 64 hex characters, the format used by
+    /// the command line tool.
+    pub fn add_hex(&mut self, key: &str) -> Result<(), BinsyncError> {
+        let invalid = || BinsyncError::InvalidKey(key.to_string());
+
+        let bytes = parse_hex::<32>(key.trim()).ok_or_else(invalid)?;
+        self.add(VerifyingKey::from_bytes(&bytes).map_err(|_| invalid())?);
+
+        Ok(())
+    }
+
+    /// Checks the signature was made over the data by a trusted key.
+    pub(crate) fn verify(
+        &self,
+        data: &[u8],
+        signature: Option<&ManifestSignature>,
+    ) -> Result<(), BinsyncError> {
+        let signature = signature.ok_or(BinsyncError::MissingSignature)?;
+
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.as_bytes() == &signature.key)
+            .ok_or(BinsyncError::InvalidSignature)?;
+
+        key.verify_strict(data, &Signature::from_bytes(&signature.signature))
+            .map_err(|_| BinsyncError::InvalidSignature)
+    }
+}
+
+/// Reads a secret signing key written as 64 hex characters, the format
+/// written by the command line tool.
+pub fn signing_key_from_hex(text: &str) -> Result<SigningKey, BinsyncError> {
+    let bytes =
+        parse_hex::<32>(text.trim()).ok_or_else(|| BinsyncError::InvalidKey(text.to_string()))?;
+
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// Parses a fixed number of bytes written as hex characters.
+pub(crate) fn parse_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != N * 2 || !text.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+    for (byte, pair) in bytes.iter_mut().zip(text.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+
+    Some(bytes)
+}
+
+/// Writes byte arrays as hex strings in human readable formats and as raw
+/// bytes in binary ones. Serde only supports arrays of up to 32 elements on
+/// its own.
+mod fixed_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        bytes: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            return serializer.serialize_str(&hex);
+        }
+
+        let mut tuple = serializer.serialize_tuple(N)?;
+        for byte in bytes {
+            tuple.serialize_element(byte)?;
+        }
+        tuple.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        if deserializer.is_human_readable() {
+            let text = String::deserialize(deserializer)?;
+            return parse_hex(&text).ok_or_else(|| de::Error::custom("invalid hex bytes"));
+        }
+
+        deserializer.deserialize_tuple(N, BytesVisitor::<N>)
+    }
+
+    struct BytesVisitor<const N: usize>;
+
+    impl<'de, const N: usize> Visitor<'de> for BytesVisitor<N> {
+        type Value = [u8; N];
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{} bytes", N)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut bytes = [0; N];
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+            }
+
+            Ok(bytes)
+        }
+    }
+}
@@ -0,0 +1,301 @@
+#[cfg(feature = "tar")]
+use std::path::Path;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Read},
+    path::PathBuf,
+};
+
+use crate::BinsyncError;
+
+use super::{
+    filter::PathFilter,
+    manifest::{
+        check_collisions, chunk_reader, DirectoryInfo, FileMetadata, LinkInfo, LinkKind, Manifest,
+        ManifestHeader, ManifestOptions,
+    },
+    path::is_contained,
+    HashAlgorithm,
+};
+
+/// A single entry read from a `ManifestSource`. Paths are relative to the
+/// root of the source.
+pub enum SourceEntry<'a> {
+    /// A regular file and a reader over its contents.
+    File {
+        path: PathBuf,
+        metadata: FileMetadata,
+        reader: &'a mut dyn Read,
+    },
+    Directory(PathBuf),
+    Link(LinkInfo),
+}
+
+/// Something other than a directory on disk that a manifest can be generated
+/// from, such as an archive or data held in memory. Entries are read once and
+/// in order so a source can be a stream.
+pub trait ManifestSource {
+    /// Passes every entry of the source to `visit`, stopping at the first
+    /// error.
+    fn visit(
+        &mut self,
+        visit: &mut dyn FnMut(SourceEntry<'_>) -> Result<(), BinsyncError>,
+    ) -> Result<(), BinsyncError>;
+}
+
+/// A source of files from pairs of a path and a reader over the contents.
+/// The files get no metadata and directories are only implied by paths.
+pub struct ReaderSource<I> {
+    entries: I,
+}
+
+impl<I: Iterator> ReaderSource<I> {
+    pub fn new<T: IntoIterator<IntoIter = I>>(entries: T) -> ReaderSource<I> {
+        ReaderSource {
+            entries: entries.into_iter(),
+        }
+    }
+}
+
+impl<I, P, R> ManifestSource for ReaderSource<I>
+where
+    I: Iterator<Item = (P, R)>,
+    P: Into<PathBuf>,
+    R: Read,
+{
+    fn visit(
+        &mut self,
+        visit: &mut dyn FnMut(SourceEntry<'_>) -> Result<(), BinsyncError>,
+    ) -> Result<(), BinsyncError> {
+        for (path, mut reader) in &mut self.entries {
+            visit(SourceEntry::File {
+                path: path.into(),
+                metadata: FileMetadata::default(),
+                reader: &mut reader,
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A source reading the entries of a tar stream. Regular files, directories
+/// and both kinds of links are recorded and anything else, including sparse
+/// files, is left out. The stream may be compressed as long as the reader
+/// decompresses it.
+#[cfg(feature = "tar")]
+pub struct TarSource<R: Read> {
+    archive: tar::Archive<R>,
+}
+
+#[cfg(feature = "tar")]
+impl<R: Read> TarSource<R> {
+    pub fn new(reader: R) -> TarSource<R> {
+        TarSource {
+            archive: tar::Archive::new(reader),
+        }
+    }
+}
+
+#[cfg(feature = "tar")]
+impl<R: Read> ManifestSource for TarSource<R> {
+    fn visit(
+        &mut self,
+        visit: &mut dyn FnMut(SourceEntry<'_>) -> Result<(), BinsyncError>,
+    ) -> Result<(), BinsyncError> {
+        use tar::EntryType;
+
+        for entry in self.archive.entries()? {
+            let mut entry = entry?;
+
+            let path = match tar_path(&entry.path()?) {
+                Some(path) => path,
+                None => continue,
+            };
+
+            let header = entry.header();
+            let metadata = FileMetadata {
+                mode: header.mode().ok(),
+                modified: header
+                    .mtime()
+                    .ok()
+                    .map(|seconds| super::manifest::Timestamp {
+                        seconds: seconds as i64,
+                        nanos: 0,
+                    }),
+                inode: None,
+            };
+
+            match header.entry_type() {
+                EntryType::Regular | EntryType::Continuous => visit(SourceEntry::File {
+                    path,
+                    metadata,
+                    reader: &mut entry,
+                })?,
+                EntryType::Directory => visit(SourceEntry::Directory(path))?,
+                EntryType::Symlink => visit(SourceEntry::Link(LinkInfo {
+                    path,
+                    kind: LinkKind::Symlink(link_name(&entry)?),
+                }))?,
+                EntryType::Link => {
+                    let primary = tar_path(&link_name(&entry)?).unwrap_or_default();
+                    visit(SourceEntry::Link(LinkInfo {
+                        path,
+                        kind: LinkKind::Hardlink(primary),
+                    }))?
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tar")]
+fn link_name<R: Read>(entry: &tar::Entry<'_, R>) -> io::Result<PathBuf> {
+    Ok(entry.link_name()?.unwrap_or_default().into_owned())
+}
+
+/// Tar paths often start with `./` and the archive root itself is listed as
+/// `./`, which has no path of its own.
+#[cfg(feature = "tar")]
+pub(crate) fn tar_path(path: &Path) -> Option<PathBuf> {
+    use std::path::Component;
+
+    let path: PathBuf = path
+        .components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect();
+
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+/// The kind of the last entry seen for a path of a source.
+#[derive(PartialEq, Clone, Copy)]
+enum EntryKind {
+    File,
+    Directory,
+    Link,
+}
+
+impl Manifest {
+    /// Generates a manifest from the entries of a source instead of a
+    /// directory. Files are chunked one at a time as the source is read.
+    pub fn from_source<S: ManifestSource>(source: S) -> Result<Manifest, BinsyncError> {
+        Manifest::from_source_with_options(source, &ManifestOptions::default())
+    }
+
+    /// Similar to from_source with custom generation options. Ignore files
+    /// and following links only apply to directories and are not used. When
+    /// a path shows up more than once the last entry wins, like when
+    /// extracting an archive.
+    pub fn from_source_with_options<S: ManifestSource>(
+        mut source: S,
+        options: &ManifestOptions,
+    ) -> Result<Manifest, BinsyncError> {
+        let config = options.chunker;
+        config.validate()?;
+
+        let filter = PathFilter::new(&options.include, &options.exclude)?;
+
+        let mut manifest = Manifest::new();
+        manifest.header = ManifestHeader::new(config, HashAlgorithm::Blake3);
+
+        let mut kinds = HashMap::new();
+
+        source.visit(&mut |entry| {
+            let (path, kind) = match &entry {
+                SourceEntry::File { path, .. } => (path, EntryKind::File),
+                SourceEntry::Directory(path) => (path, EntryKind::Directory),
+                SourceEntry::Link(link_info) => (&link_info.path, EntryKind::Link),
+            };
+
+            // Sources are not walked so excluded directories are not pruned.
+            if !is_contained(path) {
+                return Err(BinsyncError::UnsafePath(path.clone()));
+            }
+            if path.ancestors().any(|p| filter.is_excluded(p)) {
+                return Ok(());
+            }
+            kinds.insert(path.clone(), kind);
+
+            match entry {
+                SourceEntry::File {
+                    path,
+                    metadata,
+                    reader,
+                } if filter.is_included(&path) => {
+                    let file = chunk_reader(reader, path.clone(), config, metadata)
+                        .map_err(|source| BinsyncError::ReadFailed { path, source })?;
+                    manifest.files.push(file);
+                }
+                SourceEntry::Directory(path) => manifest.directories.push(DirectoryInfo { path }),
+                SourceEntry::Link(link_info) if filter.is_included(&link_info.path) => {
+                    manifest.links.push(link_info)
+                }
+                _ => {}
+            }
+
+            Ok(())
+        })?;
+
+        // A path may also change kind, e.g. a file replaced by a directory.
+        manifest.files.retain(|f| kinds[&f.path] == EntryKind::File);
+        manifest
+            .directories
+            .retain(|d| kinds[&d.path] == EntryKind::Directory);
+        manifest.links.retain(|l| kinds[&l.path] == EntryKind::Link);
+
+        last_wins(&mut manifest.files, |f| &f.path);
+        last_wins(&mut manifest.directories, |d| &d.path);
+        last_wins(&mut manifest.links, |l| &l.path);
+
+        // Directories are only kept if they are included themselves or hold
+        // something that is, the same as when walking a directory.
+        if !filter.is_empty() {
+            let mut parents = HashSet::new();
+            for path in manifest.files.iter().map(|f| &f.path) {
+                parents.extend(path.ancestors().skip(1));
+            }
+            for path in manifest.links.iter().map(|l| &l.path) {
+                parents.extend(path.ancestors().skip(1));
+            }
+
+            manifest
+                .directories
+                .retain(|d| filter.is_included(&d.path) || parents.contains(d.path.as_path()));
+        }
+
+        // Hard links can only point at a file the manifest holds.
+        for link_info in &manifest.links {
+            if let LinkKind::Hardlink(primary) = &link_info.kind {
+                let found = manifest
+                    .files
+                    .binary_search_by(|f| f.path.cmp(primary))
+                    .is_ok();
+
+                if !found {
+                    return Err(BinsyncError::ReadFailed {
+                        path: link_info.path.clone(),
+                        source: io::Error::new(
+                            io::ErrorKind::NotFound,
+                            "hard linked file is not in the manifest",
+                        ),
+                    });
+                }
+            }
+        }
+
+        check_collisions(&manifest, options)?;
+
+        Ok(manifest)
+    }
+}
+
+/// Sorts entries by path keeping only the last one added for each path.
+fn last_wins<T>(entries: &mut Vec<T>, path: impl Fn(&T) -> &PathBuf) {
+    entries.reverse();
+    entries.sort_by(|a, b| path(a).cmp(path(b)));
+    entries.dedup_by(|a, b| path(a) == path(b));
+}
@@ -0,0 +1,176 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use super::{manifest::Manifest, ChunkId};
+
+/// How many files are listed as the largest and most shared.
+const TOP_FILES: usize = 10;
+
+/// Chunks between the minimum and maximum chunk size are split into this many
+/// histogram buckets.
+const HISTOGRAM_BUCKETS: u64 = 8;
+
+/// Numbers describing the files and chunks in a manifest.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ManifestStats {
+    pub file_count: usize,
+    pub total_bytes: u64,
+    pub chunk_count: usize,
+    pub unique_chunks: usize,
+    pub unique_bytes: u64,
+    /// Total bytes divided by unique bytes. 1.0 means nothing is shared.
+    pub dedup_ratio: f64,
+    /// Chunk sizes from below the minimum chunk size up to the maximum. Only
+    /// the last chunk of a file can be below the minimum and chunks at the
+    /// maximum were cut without finding a boundary.
+    pub histogram: Vec<HistogramBucket>,
+    /// The largest files, largest first.
+    pub largest_files: Vec<(PathBuf, u64)>,
+    /// The files with the most chunks that also appear in other files.
+    pub most_shared: Vec<SharedFile>,
+}
+
+/// The number of chunks with a length between `min` and `max` inclusive.
+#[derive(PartialEq, Debug, Clone)]
+pub struct HistogramBucket {
+    pub min: u64,
+    pub max: u64,
+    pub chunks: usize,
+}
+
+/// How much of a file is also found in other files.
+#[derive(PartialEq, Debug, Clone)]
+pub struct SharedFile {
+    pub path: PathBuf,
+    pub shared_chunks: usize,
+    pub shared_bytes: u64,
+}
+
+impl Manifest {
+    /// Gathers statistics about the manifest without touching the disk.
+    pub fn stats(&self) -> ManifestStats {
+        let mut stats = ManifestStats {
+            file_count: self.files.len(),
+            total_bytes: 0,
+            chunk_count: 0,
+            unique_chunks: 0,
+            unique_bytes: 0,
+            dedup_ratio: 1.0,
+            histogram: histogram_buckets(
+                self.header.chunker.min_size as u64,
+                self.header.chunker.max_size as u64,
+            ),
+            largest_files: Vec::new(),
+            most_shared: Vec::new(),
+        };
+
+        let mut owners: HashMap<&ChunkId, (u64, usize, usize)> = HashMap::new();
+
+        for (index, file) in self.files.iter().enumerate() {
+            stats.total_bytes += file.length;
+            stats.chunk_count += file.chunks.len();
+
+            for chunk in &file.chunks {
+                // Anything over the maximum comes from a mismatched header
+                // and is counted with the largest chunks.
+                let last = stats.histogram.len() - 1;
+                let bucket = stats
+                    .histogram
+                    .iter()
+                    .position(|b| chunk.length <= b.max)
+                    .unwrap_or(last);
+                stats.histogram[bucket].chunks += 1;
+
+                // Tracks the length, last file seen and number of files.
+                let owner = owners
+                    .entry(&chunk.hash)
+                    .or_insert((chunk.length, index, 0));
+                if owner.2 == 0 || owner.1 != index {
+                    owner.1 = index;
+                    owner.2 += 1;
+                }
+            }
+        }
+
+        stats.unique_chunks = owners.len();
+        stats.unique_bytes = owners.values().map(|(length, _, _)| length).sum();
+        if stats.unique_bytes > 0 {
+            stats.dedup_ratio = stats.total_bytes as f64 / stats.unique_bytes as f64;
+        }
+
+        let mut largest: Vec<(PathBuf, u64)> = self
+            .files
+            .iter()
+            .map(|f| (f.path.clone(), f.length))
+            .collect();
+        largest.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        largest.truncate(TOP_FILES);
+        stats.largest_files = largest;
+
+        let mut shared: Vec<SharedFile> = self
+            .files
+            .iter()
+            .map(|file| {
+                let mut shared = SharedFile {
+                    path: file.path.clone(),
+                    shared_chunks: 0,
+                    shared_bytes: 0,
+                };
+
+                for chunk in &file.chunks {
+                    if owners[&chunk.hash].2 > 1 {
+                        shared.shared_chunks += 1;
+                        shared.shared_bytes += chunk.length;
+                    }
+                }
+
+                shared
+            })
+            .filter(|shared| shared.shared_chunks > 0)
+            .collect();
+        shared.sort_by(|a, b| {
+            b.shared_chunks
+                .cmp(&a.shared_chunks)
+                .then_with(|| a.path.cmp(&b.path))
+        });
+        shared.truncate(TOP_FILES);
+        stats.most_shared = shared;
+
+        stats
+    }
+}
+
+/// Builds empty buckets for everything below the minimum, equal steps between
+/// the minimum and maximum, and chunks exactly at the maximum. Headers are
+/// not validated here so a minimum above the maximum is clamped to it.
+fn histogram_buckets(min: u64, max: u64) -> Vec<HistogramBucket> {
+    let mut buckets = Vec::new();
+    let min = min.min(max);
+
+    if min > 0 {
+        buckets.push(HistogramBucket {
+            min: 0,
+            max: min - 1,
+            chunks: 0,
+        });
+    }
+
+    let step = ((max - min) / HISTOGRAM_BUCKETS).max(1);
+    let mut start = min;
+    while start < max {
+        let end = (start + step).min(max);
+        buckets.push(HistogramBucket {
+            min: start,
+            max: end - 1,
+            chunks: 0,
+        });
+        start = end;
+    }
+
+    buckets.push(HistogramBucket {
+        min: max,
+        max,
+        chunks: 0,
+    });
+
+    buckets
+}
@@ -0,0 +1,88 @@
+use std::{
+    cmp,
+    io::{self, Read},
+};
+
+use super::ChunkerConfig;
+
+/// Chunks a reader without holding the whole stream in memory. Chunk
+/// boundaries match chunking the entire contents at once since the chunker
+/// only ever looks at most `max_size` bytes past the start of a chunk, so the
+/// buffer is always topped up to at least that much before cutting.
+pub(crate) struct StreamChunker<R: Read> {
+    reader: R,
+    config: ChunkerConfig,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    offset: u64,
+    eof: bool,
+}
+
+impl<R: Read> StreamChunker<R> {
+    /// Creates a chunker holding at most `buffer_size` bytes of the stream.
+    /// The buffer is never smaller than twice the maximum chunk size.
+    pub fn new(reader: R, config: ChunkerConfig, buffer_size: usize) -> StreamChunker<R> {
+        let buffer_size = cmp::max(buffer_size, config.max_size as usize * 2);
+
+        StreamChunker {
+            reader,
+            config,
+            buffer: vec![0; buffer_size],
+            start: 0,
+            end: 0,
+            offset: 0,
+            eof: false,
+        }
+    }
+
+    /// Returns the offset and contents of the next chunk in the stream.
+    pub fn next_chunk(&mut self) -> io::Result<Option<(u64, &[u8])>> {
+        if self.end - self.start < self.config.max_size as usize && !self.eof {
+            self.fill()?;
+        }
+
+        if self.start == self.end {
+            return Ok(None);
+        }
+
+        let length = match self
+            .config
+            .chunker(&self.buffer[self.start..self.end])
+            .next()
+        {
+            Some(entry) => entry.length,
+            None => return Ok(None),
+        };
+
+        let offset = self.offset;
+        let start = self.start;
+
+        self.start += length;
+        self.offset += length as u64;
+
+        Ok(Some((offset, &self.buffer[start..start + length])))
+    }
+
+    /// Moves the unused data to the front of the buffer and reads until the
+    /// buffer is full or the stream ends.
+    fn fill(&mut self) -> io::Result<()> {
+        self.buffer.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+
+        while self.end < self.buffer.len() {
+            match self.reader.read(&mut self.buffer[self.end..]) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                }
+                Ok(read) => self.end += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+}
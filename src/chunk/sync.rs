@@ -1,16 +1,30 @@
 use std::{
-    collections::HashMap,
-    convert::TryInto,
+    collections::{HashMap, HashSet},
     fs::{self, OpenOptions},
     io::{BufWriter, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
 };
 
-use fastcdc::FastCDC;
+use walkdir::WalkDir;
 
-use crate::{error::Error, Manifest};
+use crate::{
+    error::Error, FileChunkInfo, FileMetadata, HashAlgorithm, LayeredManifest, LinkInfo, LinkKind,
+    Manifest, TrustedKeys,
+};
+
+use super::{
+    limits::check_chunks,
+    path::{is_contained, link_is_contained},
+    stream::StreamChunker,
+    Chunk, ChunkProvider, DeletePolicy, Operation, SyncPlan,
+};
+
+/// Permission bits applied from a manifest. Setuid, setgid and sticky bits
+/// are left out so a manifest can not grant more than plain access.
+const APPLIED_MODE: u32 = 0o777;
 
-use super::{Chunk, ChunkProvider, Operation, SyncPlan, AVG_CHUNK, MAX_CHUNK, MIN_CHUNK};
+/// Default amount of an existing file held in memory while planning.
+const DEFAULT_MEMORY_LIMIT: usize = 16777216; // 16MB
 
 /// Uses a manifest and a provider to sync data to the destination.
 pub struct Syncer<'a, T: ChunkProvider> {
@@ -18,6 +32,13 @@ pub struct Syncer<'a, T: ChunkProvider> {
     provider: T,
     manifest: Manifest,
     progress: Option<Box<dyn FnMut(u32) + 'a>>,
+    delete_policy: DeletePolicy,
+    protected: Vec<PathBuf>,
+    verify: bool,
+    memory_limit: usize,
+    trusted_keys: Option<TrustedKeys>,
+    layers: Option<LayeredManifest>,
+    held_layers: Vec<String>,
 }
 
 impl<'a, T: ChunkProvider> Syncer<'a, T> {
@@ -27,63 +48,273 @@ impl<'a, T: ChunkProvider> Syncer<'a, T> {
             provider,
             manifest,
             progress: None,
+            delete_policy: DeletePolicy::Keep,
+            protected: Vec::new(),
+            verify: true,
+            memory_limit: DEFAULT_MEMORY_LIMIT,
+            trusted_keys: None,
+            layers: None,
+            held_layers: Vec::new(),
         }
     }
 
+    /// Syncs the merged view of every layer in a single pass. Files removed
+    /// from an overlay fall back to the version in a lower layer instead of
+    /// being deleted, and the plan records which layer owns each path.
+    /// Deleting only removes paths that no layer owns.
+    pub fn with_layers<P: AsRef<Path>>(
+        destination: P,
+        provider: T,
+        layers: LayeredManifest,
+    ) -> Syncer<'a, T> {
+        let mut syncer = Syncer::new(destination, provider, layers.flatten());
+        syncer.layers = Some(layers);
+
+        syncer
+    }
+
+    /// Leaves every path owned by the named layer as it is in the
+    /// destination, e.g. an overlay that is updated on its own schedule. Its
+    /// paths are neither written nor deleted, and it still takes precedence
+    /// over the layers below it.
+    pub fn hold_layer<S: Into<String>>(&mut self, name: S) {
+        self.held_layers.push(name.into());
+    }
+
+    /// Whether the path belongs to a layer that is held by `hold_layer`.
+    fn is_held(&self, plan: &SyncPlan, path: &Path) -> bool {
+        plan.owners
+            .get(path)
+            .is_some_and(|layer| self.held_layers.contains(layer))
+    }
+
     /// Sets a function to receive progress updates. Every time a file is
     /// completed this is fired with a number from 0 percent to 100.
     pub fn on_progress(&mut self, f: impl FnMut(u32) + 'a) {
         self.progress = Some(Box::new(f));
     }
 
+    /// Sets what happens to files in the destination that are not in the
+    /// manifest. Defaults to `DeletePolicy::Keep`.
+    pub fn delete_policy(&mut self, policy: DeletePolicy) {
+        self.delete_policy = policy;
+    }
+
+    /// Protects a path relative to the destination from ever being deleted or
+    /// quarantined. Protecting a directory protects everything inside it.
+    pub fn protect<P: AsRef<Path>>(&mut self, path: P) {
+        self.protected.push(path.as_ref().to_path_buf());
+    }
+
+    /// Sets if every written file is read back and checked against the
+    /// length and digest in the manifest. Enabled by default.
+    pub fn verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Sets roughly how many bytes of an existing destination file are held
+    /// in memory at once while planning. It is never less than twice the
+    /// maximum chunk size of the manifest.
+    pub fn memory_limit(&mut self, bytes: usize) {
+        self.memory_limit = bytes;
+    }
+
+    /// Only accepts a manifest signed by one of the given keys. Nothing is
+    /// planned or synced for a manifest that is unsigned or was changed.
+    pub fn trusted_keys(&mut self, keys: TrustedKeys) {
+        self.trusted_keys = Some(keys);
+    }
+
+    /// Makes sure the manifest can be synced safely before touching anything.
+    fn accept_manifest(&self) -> Result<(), Error> {
+        // Existing files need to be chunked the same way as the manifest.
+        self.manifest.header.validate()?;
+
+        // Chunk lengths decide how much is read into memory at once.
+        check_chunks(&self.manifest)?;
+
+        // A merged manifest is never signed itself, only its layers are.
+        match (&self.trusted_keys, &self.layers) {
+            (Some(keys), Some(layers)) => layers.verify_signatures(keys)?,
+            (Some(keys), None) => self.manifest.verify_signature(keys)?,
+            (None, _) => {}
+        }
+
+        self.check_paths()
+    }
+
+    /// Makes sure nothing in the manifest can be written outside of the
+    /// destination, either through its own paths and links or through
+    /// symbolic links already in the destination.
+    fn check_paths(&self) -> Result<(), Error> {
+        let manifest = &self.manifest;
+        let paths = manifest
+            .files
+            .iter()
+            .map(|f| &f.path)
+            .chain(manifest.directories.iter().map(|d| &d.path))
+            .chain(manifest.links.iter().map(|l| &l.path));
+
+        for path in paths.clone() {
+            if !is_contained(path) {
+                return Err(Error::UnsafePath(path.clone()));
+            }
+        }
+
+        let symlinks: HashMap<&Path, &Path> = manifest
+            .links
+            .iter()
+            .filter_map(|link_info| match &link_info.kind {
+                LinkKind::Symlink(target) => Some((link_info.path.as_path(), target.as_path())),
+                LinkKind::Hardlink(_) => None,
+            })
+            .collect();
+
+        // A symbolic link may lead outside, like one to a system library, as
+        // long as nothing in the manifest is written through it. Links that
+        // already exist in the destination are checked below.
+        let parents: HashSet<&Path> = paths
+            .clone()
+            .flat_map(|path| path.ancestors().skip(1))
+            .collect();
+
+        for link_info in &manifest.links {
+            let contained = match &link_info.kind {
+                LinkKind::Symlink(target) => {
+                    !parents.contains(link_info.path.as_path())
+                        || link_is_contained(&link_info.path, target, &symlinks)
+                }
+                LinkKind::Hardlink(primary) => is_contained(primary),
+            };
+
+            if !contained {
+                return Err(Error::UnsafePath(link_info.path.clone()));
+            }
+        }
+
+        // Nothing exists below a destination that does not exist yet.
+        let root = match fs::canonicalize(&self.destination) {
+            Ok(root) => root,
+            Err(_) => return Ok(()),
+        };
+
+        let mut checked = HashSet::new();
+        for path in paths {
+            for parent in path.ancestors().skip(1) {
+                if parent.as_os_str().is_empty() || !checked.insert(parent) {
+                    continue;
+                }
+
+                let full = self.destination.join(parent);
+                let is_symlink = fs::symlink_metadata(&full).is_ok_and(|m| m.is_symlink());
+
+                // Writing below a link that leaves the destination, or that
+                // points nowhere, would create files wherever it leads.
+                if is_symlink && !fs::canonicalize(&full).is_ok_and(|p| p.starts_with(&root)) {
+                    return Err(Error::UnsafePath(path.clone()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Plans an update with the current `Manifest` and settings. Returns a plan
     /// of what files should update with a list of operations for each file.
     pub fn plan(&self) -> Result<SyncPlan, Error> {
+        self.accept_manifest()?;
+
         let mut plan = SyncPlan {
             operations: Vec::new(),
             total_ops: 0,
+            directories: Vec::new(),
+            links: Vec::new(),
+            deletions: Vec::new(),
+            owners: self
+                .layers
+                .as_ref()
+                .map(LayeredManifest::owners)
+                .unwrap_or_default(),
         };
 
+        for directory_info in &self.manifest.directories {
+            if self.is_held(&plan, &directory_info.path) {
+                continue;
+            }
+
+            if !self.destination.join(&directory_info.path).is_dir() {
+                plan.directories.push(directory_info.path.clone());
+            }
+        }
+
         let mut total_ops = 0;
 
         // TODO: We could parallelize this per-file or per-slice to get better
         // usage of the CPU cores and always be utilizing disk I/O
         for file_chunk_info in &self.manifest.files {
+            if self.is_held(&plan, &file_chunk_info.path) {
+                continue;
+            }
+
             let mut operations = Vec::new();
             let path = self.destination.join(&file_chunk_info.path);
 
             let mut have_chunks = HashMap::new();
 
+            // Every all-zero chunk of a length shares one id, so they are
+            // found by where they are instead.
+            let mut zero_chunks = HashSet::new();
+
+            // Links and folders in the way are replaced rather than read.
+            let is_file = fs::symlink_metadata(&path).is_ok_and(|m| m.is_file());
+
             // If we have an existing file extract chunks from it.
-            if path.exists() {
-                let mut source_file = OpenOptions::new()
+            if is_file {
+                let source_file = OpenOptions::new()
                     .read(true)
                     .open(&path)
                     .map_err(|_| Error::AccessDenied)?;
 
-                // TODO: We read the entire file to memory. Instead we should
-                // be able to do this in subsections based on a max memory limit.
-                let mut contents = Vec::new();
-                source_file
-                    .read_to_end(&mut contents)
-                    .map_err(|_| Error::AccessDenied)?;
-                let chunker = FastCDC::new(&contents, MIN_CHUNK, AVG_CHUNK, MAX_CHUNK);
+                // Only up to the memory limit of the file is held at once.
+                let config = self.manifest.header.chunker;
+                let mut chunker = StreamChunker::new(source_file, config, self.memory_limit);
 
-                for entry in chunker {
-                    let end = entry.offset + entry.length;
-                    let data = &contents[entry.offset..end];
+                while let Some((offset, data)) = chunker.next_chunk()? {
+                    let hash = self.manifest.header.hash.digest(data);
 
-                    let digest = md5::compute(data);
-                    let hash = u64::from_le_bytes(digest[0..8].try_into().unwrap());
+                    let zero = data.iter().all(|&b| b == 0);
+                    if zero {
+                        zero_chunks.insert((offset, data.len() as u64));
+                    }
 
-                    have_chunks.insert(hash, entry);
+                    have_chunks.insert(
+                        hash,
+                        Chunk {
+                            hash,
+                            offset,
+                            length: data.len() as u64,
+                            zero,
+                        },
+                    );
                 }
             }
 
             for chunk in file_chunk_info.chunks.iter() {
+                // Zeros are never copied around, only left as holes.
+                if chunk.zero {
+                    if zero_chunks.contains(&(chunk.offset, chunk.length)) {
+                        operations.push(Operation::Seek(chunk.length as i64));
+                    } else {
+                        operations.push(Operation::Hole(chunk.length));
+                    }
+
+                    total_ops += 1;
+                    continue;
+                }
+
                 match have_chunks.get(&chunk.hash) {
                     Some(entry) => {
-                        if entry == chunk {
+                        if entry.offset == chunk.offset && entry.length == chunk.length {
                             // The chunk is already in the right place.
                             let seek_len: i64 = entry.length as i64;
                             operations.push(Operation::Seek(seek_len));
@@ -91,8 +322,9 @@ impl<'a, T: ChunkProvider> Syncer<'a, T> {
                             // We have the same chunk, but elsewhere in the file.
                             operations.push(Operation::Copy(Chunk {
                                 hash: chunk.hash,
-                                offset: entry.offset as u64,
-                                length: entry.length as u64,
+                                offset: entry.offset,
+                                length: entry.length,
+                                zero: false,
                             }));
                         }
                     }
@@ -102,17 +334,17 @@ impl<'a, T: ChunkProvider> Syncer<'a, T> {
                             hash: chunk.hash,
                             offset: chunk.offset,
                             length: chunk.length,
+                            zero: false,
                         }));
                     }
                 }
 
-                total_ops = total_ops + 1;
+                total_ops += 1;
             }
 
             // If the files are the same just skip this entirely.
-            let should_skip = !operations
-                .iter()
-                .any(|op| matches!(op, Operation::Copy(_)) || matches!(op, Operation::Fetch(_)));
+            let should_skip =
+                is_file && operations.iter().all(|op| matches!(op, Operation::Seek(_)));
 
             if !should_skip {
                 plan.operations
@@ -121,9 +353,115 @@ impl<'a, T: ChunkProvider> Syncer<'a, T> {
             }
         }
 
+        // Hard links also need to be redone when the file they point to gets
+        // written since writing can give that file a new identity.
+        for link_info in &self.manifest.links {
+            if self.is_held(&plan, &link_info.path) {
+                continue;
+            }
+
+            let primary_written = match &link_info.kind {
+                LinkKind::Hardlink(primary) => plan.operations.iter().any(|(p, _)| p == primary),
+                LinkKind::Symlink(_) => false,
+            };
+
+            if primary_written || !self.link_matches(link_info) {
+                self.check_link_path(&link_info.path)?;
+                plan.links.push(link_info.clone());
+            }
+        }
+
+        if self.delete_policy != DeletePolicy::Keep {
+            plan.deletions = self.plan_deletions()?;
+        }
+
         Ok(plan)
     }
 
+    /// A real directory where a link belongs only makes way for the link when
+    /// the delete policy empties it first, since it may hold user data.
+    fn check_link_path(&self, relative: &Path) -> Result<(), Error> {
+        let path = self.destination.join(relative);
+        if !fs::symlink_metadata(&path).is_ok_and(|m| m.is_dir()) {
+            return Ok(());
+        }
+
+        let is_empty = fs::read_dir(&path)?.next().is_none();
+        let is_protected = self
+            .protected
+            .iter()
+            .any(|p| p.starts_with(relative) || relative.starts_with(p));
+
+        if is_protected || (!is_empty && self.delete_policy == DeletePolicy::Keep) {
+            return Err(Error::LinkBlocked(relative.to_path_buf()));
+        }
+
+        Ok(())
+    }
+
+    /// Walks the destination looking for anything the manifest does not list.
+    /// Directories are only removed if nothing inside of them is kept.
+    fn plan_deletions(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut deletions = Vec::new();
+
+        if !self.destination.is_dir() {
+            return Ok(deletions);
+        }
+
+        // Every entry in the manifest keeps itself and its parent folders. A
+        // layered manifest lists every path some layer owns, held or not.
+        let paths = self
+            .manifest
+            .files
+            .iter()
+            .map(|f| &f.path)
+            .chain(self.manifest.directories.iter().map(|d| &d.path))
+            .chain(self.manifest.links.iter().map(|l| &l.path));
+
+        let mut keep = HashSet::new();
+        for path in paths {
+            for ancestor in path.ancestors() {
+                keep.insert(ancestor.to_path_buf());
+            }
+        }
+
+        // A quarantine folder inside the destination must not quarantine itself.
+        // Both are resolved first so different spellings of the same folder,
+        // like a relative and an absolute path, still match.
+        let mut protected = self.protected.clone();
+        if let DeletePolicy::Quarantine(folder) = &self.delete_policy {
+            let destination = fs::canonicalize(&self.destination)?;
+            if let Ok(relative) = resolve(folder)?.strip_prefix(&destination) {
+                protected.push(relative.to_path_buf());
+            }
+        }
+
+        // Walking contents first means a directory is visited after everything
+        // inside of it has already been decided.
+        for entry in WalkDir::new(&self.destination)
+            .min_depth(1)
+            .contents_first(true)
+        {
+            let entry = entry.map_err(std::io::Error::from)?;
+            let relative = match entry.path().strip_prefix(&self.destination) {
+                Ok(relative) => relative.to_path_buf(),
+                Err(_) => continue,
+            };
+
+            let is_protected = protected.iter().any(|p| relative.starts_with(p));
+
+            if is_protected || keep.contains(&relative) {
+                for ancestor in relative.ancestors() {
+                    keep.insert(ancestor.to_path_buf());
+                }
+            } else {
+                deletions.push(relative);
+            }
+        }
+
+        Ok(deletions)
+    }
+
     /// Exectues a sync from source to destination with the current parameters.
     pub fn sync(&mut self) -> Result<(), Error> {
         let plan = self.plan()?;
@@ -133,9 +471,26 @@ impl<'a, T: ChunkProvider> Syncer<'a, T> {
 
     /// Executes a sync from the given plan.
     pub fn sync_from_plan(&mut self, plan: &SyncPlan) -> Result<(), Error> {
+        self.accept_manifest()?;
+
         let mut ops_completed: u32 = 0;
 
-        self.provider.set_plan(&plan);
+        let files: HashMap<&Path, &FileChunkInfo> = self
+            .manifest
+            .files
+            .iter()
+            .map(|f| (f.path.as_path(), f))
+            .collect();
+
+        self.provider.set_plan(plan);
+
+        for relative in &plan.deletions {
+            self.remove_path(relative)?;
+        }
+
+        for directory in &plan.directories {
+            fs::create_dir_all(self.destination.join(directory))?;
+        }
 
         for (file_path, operations) in &plan.operations {
             let path = self.destination.join(file_path);
@@ -144,13 +499,21 @@ impl<'a, T: ChunkProvider> Syncer<'a, T> {
             let parent = path
                 .parent()
                 .ok_or_else(|| Error::FileNotFound(path.to_path_buf()))?;
-            fs::create_dir_all(&parent)?;
+            fs::create_dir_all(parent)?;
+
+            // Read-only files from an earlier sync need to be writable again.
+            detach(&path)?;
+            make_writable(&path)?;
 
             let mut source_file = OpenOptions::new()
                 .read(true)
                 .write(true)
                 .create(true)
-                .open(path)?;
+                .truncate(false)
+                .open(&path)?;
+
+            // Holes past the old end of the file are left by seeking alone.
+            let old_length = source_file.metadata()?.len();
 
             let mut have_chunks = HashMap::new();
 
@@ -183,17 +546,30 @@ impl<'a, T: ChunkProvider> Syncer<'a, T> {
                     Operation::Copy(chunk) => {
                         let data = have_chunks
                             .get(&chunk.hash)
-                            .ok_or_else(|| Error::ChunkNotFound(chunk.hash))?;
+                            .ok_or(Error::ChunkNotFound(chunk.hash))?;
 
                         writer.write_all(data).map_err(|_| Error::AccessDenied)?;
                     }
                     Operation::Fetch(chunk) => {
                         let data = self.provider.get_chunk(&chunk.hash)?;
-                        writer.write_all(&data).map_err(|_| Error::AccessDenied)?;
+                        writer.write_all(data).map_err(|_| Error::AccessDenied)?;
+                    }
+                    Operation::Hole(len) => {
+                        writer.flush()?;
+                        let offset = writer.stream_position()?;
+
+                        if offset < old_length {
+                            let end = (offset + len).min(old_length);
+                            punch_hole(&source_file, offset, end - offset)?;
+                        }
+
+                        writer
+                            .seek(SeekFrom::Start(offset + len))
+                            .map_err(|_| Error::AccessDenied)?;
                     }
                 }
 
-                ops_completed = ops_completed + 1;
+                ops_completed += 1;
 
                 // Update our progress
                 if let Some(f) = &mut self.progress {
@@ -203,12 +579,312 @@ impl<'a, T: ChunkProvider> Syncer<'a, T> {
             }
 
             // Truncate the file to the correct length.
-            let pos = writer
-                .seek(SeekFrom::Current(0))
-                .map_err(|_| Error::AccessDenied)?;
+            let pos = writer.stream_position().map_err(|_| Error::AccessDenied)?;
             source_file.set_len(pos).map_err(|_| Error::AccessDenied)?;
+
+            if self.verify {
+                if let Some(file_chunk_info) = files.get(file_path.as_path()) {
+                    verify_file(&path, file_chunk_info, self.manifest.header.hash)?;
+                }
+            }
+        }
+
+        for link_info in &plan.links {
+            if !self.link_matches(link_info) {
+                self.create_link(link_info)?;
+            }
+        }
+
+        // Metadata is applied to every file, not only the ones written above,
+        // so files with matching contents still pick up permission changes.
+        for file_chunk_info in &self.manifest.files {
+            if self.is_held(plan, &file_chunk_info.path) {
+                continue;
+            }
+
+            let path = self.destination.join(&file_chunk_info.path);
+            apply_metadata(&path, &file_chunk_info.metadata)?;
         }
 
         Ok(())
     }
+
+    /// Checks if the link already exists in the destination as described.
+    fn link_matches(&self, link_info: &LinkInfo) -> bool {
+        let path = self.destination.join(&link_info.path);
+
+        match &link_info.kind {
+            LinkKind::Symlink(target) => fs::read_link(&path).is_ok_and(|t| &t == target),
+            LinkKind::Hardlink(primary) => is_same_file(&path, &self.destination.join(primary)),
+        }
+    }
+
+    /// Replaces whatever is at the link path with the link itself.
+    fn create_link(&self, link_info: &LinkInfo) -> Result<(), Error> {
+        let path = self.destination.join(&link_info.path);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // The planned deletions already emptied a real directory here, and
+        // anything left inside it is kept by removing only an empty one.
+        if let Ok(metadata) = fs::symlink_metadata(&path) {
+            if metadata.is_dir() {
+                fs::remove_dir(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        match &link_info.kind {
+            LinkKind::Symlink(target) => symlink(target, &path)?,
+            LinkKind::Hardlink(primary) => fs::hard_link(self.destination.join(primary), &path)?,
+        }
+
+        Ok(())
+    }
+
+    /// Removes or quarantines a single path from the plan's deletions.
+    fn remove_path(&self, relative: &Path) -> Result<(), Error> {
+        let path = self.destination.join(relative);
+
+        if self.delete_policy == DeletePolicy::Keep {
+            return Ok(());
+        }
+
+        // Directories are planned after their contents so they should be empty.
+        if fs::symlink_metadata(&path)?.is_dir() {
+            fs::remove_dir(&path)?;
+            return Ok(());
+        }
+
+        match &self.delete_policy {
+            DeletePolicy::Keep => {}
+            DeletePolicy::Delete => fs::remove_file(&path)?,
+            DeletePolicy::Quarantine(folder) => {
+                let target = folder.join(relative);
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                // Renaming fails across devices so fall back to copying.
+                if fs::rename(&path, &target).is_err() {
+                    fs::copy(&path, &target)?;
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Clears the read-only flag on an existing file so it can be opened for
+/// writing. Missing files are ignored.
+fn make_writable(path: &Path) -> Result<(), Error> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(()),
+    };
+
+    let mut permissions = metadata.permissions();
+    if !permissions.readonly() {
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        permissions.set_mode(permissions.mode() | 0o200);
+    }
+
+    #[cfg(not(unix))]
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+
+    fs::set_permissions(path, permissions)?;
+
+    Ok(())
+}
+
+/// Applies the manifest metadata to a file in the destination, skipping
+/// anything that already matches.
+fn apply_metadata(path: &Path, expected: &FileMetadata) -> Result<(), Error> {
+    let current = FileMetadata::from_metadata(&fs::metadata(path)?);
+
+    // The modified time goes first since some platforms need write access to
+    // change it and the permissions may take that away.
+    if let Some(modified) = expected.modified {
+        if current.modified != Some(modified) {
+            let mut options = OpenOptions::new();
+
+            #[cfg(windows)]
+            {
+                use std::os::windows::fs::OpenOptionsExt;
+                const FILE_WRITE_ATTRIBUTES: u32 = 0x100;
+                options.access_mode(FILE_WRITE_ATTRIBUTES);
+            }
+
+            #[cfg(not(windows))]
+            options.read(true);
+
+            options.open(path)?.set_modified(modified.try_into()?)?;
+        }
+    }
+
+    if let Some(mode) = expected.mode.map(|mode| mode & APPLIED_MODE) {
+        if current.mode != Some(mode) {
+            let mut permissions = fs::metadata(path)?.permissions();
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                permissions.set_mode(mode);
+            }
+
+            // Without Unix modes the best we can do is the read-only flag.
+            #[cfg(not(unix))]
+            permissions.set_readonly(mode & 0o222 == 0);
+
+            fs::set_permissions(path, permissions)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes sure writing to the path only changes that path. Symbolic links are
+/// removed and hard links are split off into their own copy of the file.
+fn detach(path: &Path) -> Result<(), Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(()),
+    };
+
+    if metadata.file_type().is_symlink() {
+        fs::remove_file(path)?;
+        return Ok(());
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        if metadata.is_file() && metadata.nlink() > 1 {
+            let mut copy = path.as_os_str().to_owned();
+            copy.push(".binsync");
+
+            fs::copy(path, &copy)?;
+            fs::rename(&copy, path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Canonicalizes a path that may not exist yet by resolving its deepest
+/// existing ancestor and appending the rest.
+fn resolve(path: &Path) -> Result<PathBuf, Error> {
+    let path = std::path::absolute(path)?;
+
+    for ancestor in path.ancestors() {
+        if let Ok(resolved) = fs::canonicalize(ancestor) {
+            let rest = path.strip_prefix(ancestor).unwrap_or(Path::new(""));
+            return Ok(resolved.join(rest));
+        }
+    }
+
+    Ok(path)
+}
+
+/// Turns part of a file into a hole so it reads as zeros without taking up
+/// space, or writes zeros where the file system does not support holes.
+fn punch_hole(file: &fs::File, offset: u64, length: u64) -> Result<(), Error> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        // The descriptor stays open for the whole call and the range is
+        // checked by the kernel.
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        let result = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                mode,
+                offset as libc::off_t,
+                length as libc::off_t,
+            )
+        };
+
+        if result == 0 {
+            return Ok(());
+        }
+    }
+
+    let mut file = file;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let zeros = vec![0; length.min(65536) as usize];
+    let mut remaining = length;
+    while remaining > 0 {
+        let amount = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..amount])?;
+        remaining -= amount as u64;
+    }
+
+    Ok(())
+}
+
+/// Checks if both paths are the same underlying file.
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::symlink_metadata(a), fs::symlink_metadata(b)) {
+        (Ok(a), Ok(b)) => a.is_file() && a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Without a portable file identity hard links are always recreated.
+#[cfg(not(unix))]
+fn is_same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    // Windows needs to know up front if the link points at a directory.
+    let resolved = path
+        .parent()
+        .map_or(target.to_path_buf(), |p| p.join(target));
+
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, path)
+    } else {
+        std::os::windows::fs::symlink_file(target, path)
+    }
+}
+
+/// Reads back a written file and checks it against the manifest.
+fn verify_file(
+    path: &Path,
+    file_chunk_info: &FileChunkInfo,
+    hash: HashAlgorithm,
+) -> Result<(), Error> {
+    let file = fs::File::open(path)?;
+    let (digest, length) = hash.digest_reader(file)?;
+
+    let digest_matches = file_chunk_info.digest.is_none_or(|d| d == digest);
+    if length != file_chunk_info.length || !digest_matches {
+        return Err(Error::VerificationFailed(path.to_path_buf()));
+    }
+
+    Ok(())
 }
@@ -1,16 +1,69 @@
 use std::path::PathBuf;
 use thiserror::Error;
 
+use crate::ChunkId;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("File not found {0}")]
     FileNotFound(PathBuf),
 
+    #[error("Invalid pattern {0}")]
+    InvalidPattern(#[from] globset::Error),
+
+    #[error("Invalid ignore file {path}: {reason}")]
+    InvalidIgnoreFile { path: PathBuf, reason: String },
+
+    #[error("Failed to read {path}: {source}")]
+    ReadFailed {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
     #[error("Directory not found {0}")]
     DirectoryNotFound(PathBuf),
 
+    #[error("Invalid digest {0}")]
+    InvalidDigest(String),
+
     #[error("Chunk not found {0}")]
-    ChunkNotFound(u64),
+    ChunkNotFound(ChunkId),
+
+    #[error("Chunk {0} does not match its id")]
+    CorruptChunk(ChunkId),
+
+    #[error("File does not match the manifest after syncing {0}")]
+    VerificationFailed(PathBuf),
+
+    #[error("Unsupported manifest version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("Invalid chunker sizes min {min} avg {avg} max {max}")]
+    InvalidChunkerConfig { min: u32, avg: u32, max: u32 },
+
+    #[error("Manifest is not signed")]
+    MissingSignature,
+
+    #[error("Manifest signature is invalid or not from a trusted key")]
+    InvalidSignature,
+
+    #[error("Invalid key {0}")]
+    InvalidKey(String),
+
+    #[error("Layer {0} has a duplicate name or is chunked differently than the base")]
+    InvalidLayer(String),
+
+    #[error("{} and {} are the same path on some file systems", first.display(), second.display())]
+    PathCollision { first: PathBuf, second: PathBuf },
+
+    #[error("Invalid manifest: {0}")]
+    InvalidManifest(String),
+
+    #[error("Manifest path {0} leads outside the destination")]
+    UnsafePath(PathBuf),
+
+    #[error("Directory {0} is in the way of a link and is kept by the delete policy")]
+    LinkBlocked(PathBuf),
 
     #[error("Access is denied")]
     AccessDenied,
@@ -20,4 +73,14 @@ pub enum Error {
 
     #[error(transparent)]
     IOError(#[from] std::io::Error),
+
+    #[error(transparent)]
+    BincodeError(#[from] bincode::Error),
+
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+
+    #[cfg(feature = "cbor")]
+    #[error("CBOR error: {0}")]
+    CborError(String),
 }
@@ -10,7 +10,7 @@
 //! ```rust,no_run
 //! use binsync::{Manifest, CachingChunkProvider, Syncer};
 //!
-//! let manifest = Manifest::from_path("foo/source");
+//! let manifest = Manifest::from_path("foo/source").unwrap();
 //!
 //! let basic_provider = CachingChunkProvider::new("foo/source");
 //!
@@ -24,25 +24,47 @@ mod sync;
 
 #[cfg(feature = "network")]
 pub use chunk::network::{RemoteChunkProvider, RemoteManifest};
+#[cfg(feature = "tar")]
+pub use chunk::{provider::TarChunkProvider, source::TarSource};
 
 pub use chunk::{
-    manifest::{FileChunkInfo, Manifest},
+    diff::{FileDiff, ManifestDiff, RenamedFile},
+    layered::{Layer, LayeredManifest},
+    limits::ManifestLimits,
+    manifest::{
+        DirectoryInfo, FileChunkInfo, FileMetadata, LinkInfo, LinkKind, Manifest, ManifestHeader,
+        ManifestOptions, SkippedFile, Timestamp, FORMAT_VERSION,
+    },
+    path::{CollisionKind, PathCollision},
     provider::CachingChunkProvider,
+    signing::{signing_key_from_hex, ManifestSignature, TrustedKeys},
+    source::{ManifestSource, ReaderSource, SourceEntry},
+    stats::{HistogramBucket, ManifestStats, SharedFile},
     sync::Syncer,
-    Chunk, ChunkProvider,
+    Chunk, ChunkId, ChunkProvider, ChunkerConfig, ChunkingAlgorithm, DeletePolicy, Digest,
+    HashAlgorithm,
 };
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
 pub use error::Error as BinsyncError;
 use std::path::Path;
 
 // Helper function to generate a manifest from the given path.
 pub fn generate_manifest(from: &str) -> Result<Manifest, BinsyncError> {
+    generate_manifest_with_options(from, &ManifestOptions::default())
+}
+
+/// Helper function to generate a manifest from the given path with custom
+/// options, such as include and exclude patterns.
+pub fn generate_manifest_with_options(
+    from: &str,
+    options: &ManifestOptions,
+) -> Result<Manifest, BinsyncError> {
     let from_path = Path::new(&from);
     if !from_path.exists() {
         return Err(BinsyncError::FileNotFound(from_path.to_path_buf()));
     }
 
-    let manifest = Manifest::from_path(from_path);
-    Ok(manifest)
+    Manifest::from_path_with_options(from_path, options)
 }
 
 /// Helper function to execute the syncer on a given path with a provider and
@@ -62,7 +84,7 @@ pub fn sync_from_manifest<T: ChunkProvider>(
 /// Helper function to sync the given input and output directories using the
 /// `CachingChunkProvider`.
 pub fn sync(from: &str, to: &str) -> Result<(), BinsyncError> {
-    let manifest = generate_manifest(&from)?;
+    let manifest = generate_manifest(from)?;
 
     let from_path = Path::new(&from);
     let provider = CachingChunkProvider::new(from_path);
@@ -77,7 +99,7 @@ pub fn sync_with_progress(
     to: &str,
     on_progress: impl FnMut(u32),
 ) -> Result<(), BinsyncError> {
-    let manifest = generate_manifest(&from)?;
+    let manifest = generate_manifest(from)?;
 
     let from_path = Path::new(&from);
     let provider = CachingChunkProvider::new(from_path);
@@ -15,6 +15,12 @@ pub struct TestContext {
     pub keep_files: bool,
 }
 
+impl Default for TestContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TestContext {
     pub fn new() -> TestContext {
         let base = thread_rng()
@@ -39,7 +45,7 @@ impl TestContext {
     }
 
     pub fn write_file(&self, path: &str, size: u64) {
-        let path_str = self.path(&path);
+        let path_str = self.path(path);
         let path = Path::new(&path_str);
 
         if let Some(parent) = path.parent() {
@@ -65,8 +71,8 @@ impl TestContext {
     }
 
     pub fn compare_hashes(&self, a: &str, b: &str) -> bool {
-        let source = fs::read(self.path(&a)).unwrap();
-        let dest = fs::read(self.path(&b)).unwrap();
+        let source = fs::read(self.path(a)).unwrap();
+        let dest = fs::read(self.path(b)).unwrap();
 
         let mut source_hasher = Sha256::new();
         source_hasher.update(source);
@@ -1,6 +1,13 @@
-use std::{fs, path::Path};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
-use binsync::{CachingChunkProvider, Syncer};
+use binsync::{
+    BinsyncError, CachingChunkProvider, ChunkerConfig, ChunkingAlgorithm, CollisionKind,
+    DeletePolicy, Digest, DirectoryInfo, HashAlgorithm, LayeredManifest, LinkKind, Manifest,
+    ManifestLimits, ManifestOptions, ReaderSource, SigningKey, Syncer, TrustedKeys,
+};
 
 extern crate binsync;
 
@@ -108,3 +115,1535 @@ fn test_copy_destination_padded() {
 
     assert!(context.compare_hashes("in/test.bin", "out/test.bin"));
 }
+
+#[test]
+/// Files and folders that are not in the source are removed from the
+/// destination unless they are protected.
+fn test_delete_extra_files() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/foo/test.bin", 1048576); // 1MB
+    context.write_file("out/foo/stale.bin", 1024);
+    context.write_file("out/bar/baz/stale.bin", 1024);
+    context.write_file("out/config/user.cfg", 1024);
+
+    let from = context.path("in");
+    let manifest = binsync::generate_manifest(&from).unwrap();
+    let provider = CachingChunkProvider::new(&from);
+
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    syncer.delete_policy(DeletePolicy::Delete);
+    syncer.protect("config");
+    syncer.sync().unwrap();
+
+    assert!(context.compare_hashes("in/foo/test.bin", "out/foo/test.bin"));
+    assert!(!Path::new(&context.path("out/foo/stale.bin")).exists());
+    assert!(!Path::new(&context.path("out/bar")).exists());
+    assert!(Path::new(&context.path("out/config/user.cfg")).exists());
+}
+
+#[test]
+/// Extra files are moved into the quarantine folder instead of deleted, and
+/// the default policy leaves them alone.
+fn test_quarantine_extra_files() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/test.bin", 1048576); // 1MB
+    context.write_file("out/foo/stale.bin", 1024);
+
+    binsync::sync(&context.path("in"), &context.path("out")).unwrap();
+    assert!(Path::new(&context.path("out/foo/stale.bin")).exists());
+
+    let from = context.path("in");
+    let manifest = binsync::generate_manifest(&from).unwrap();
+    let provider = CachingChunkProvider::new(&from);
+
+    let quarantine = context.path("out/.quarantine");
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    syncer.delete_policy(DeletePolicy::Quarantine(quarantine.into()));
+
+    let plan = syncer.plan().unwrap();
+    assert_eq!(2, plan.deletions.len());
+
+    syncer.sync_from_plan(&plan).unwrap();
+
+    assert!(!Path::new(&context.path("out/foo")).exists());
+    assert!(Path::new(&context.path("out/.quarantine/foo/stale.bin")).exists());
+    assert!(context.compare_hashes("in/test.bin", "out/test.bin"));
+
+    // The same folder spelled as an absolute path is still left alone.
+    context.write_file("out/other.bin", 1024);
+
+    let manifest = binsync::generate_manifest(&from).unwrap();
+    let provider = CachingChunkProvider::new(&from);
+
+    let quarantine = fs::canonicalize(context.path("out/.quarantine")).unwrap();
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    syncer.delete_policy(DeletePolicy::Quarantine(quarantine));
+
+    let plan = syncer.plan().unwrap();
+    assert_eq!(vec![PathBuf::from("other.bin")], plan.deletions);
+}
+
+#[test]
+/// Empty folders in the source are created in the destination and empty
+/// folders that are not in the source are removed when deleting.
+fn test_empty_directories() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/test.bin", 1024);
+    fs::create_dir_all(context.path("in/logs/old")).unwrap();
+    fs::create_dir_all(context.path("out/saves")).unwrap();
+
+    let from = context.path("in");
+    let manifest = binsync::generate_manifest(&from).unwrap();
+    assert_eq!(2, manifest.directories.len());
+
+    let provider = CachingChunkProvider::new(&from);
+
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    syncer.delete_policy(DeletePolicy::Delete);
+    syncer.sync().unwrap();
+
+    assert!(Path::new(&context.path("out/logs/old")).is_dir());
+    assert!(!Path::new(&context.path("out/saves")).exists());
+    assert!(context.compare_hashes("in/test.bin", "out/test.bin"));
+}
+
+#[cfg(unix)]
+#[test]
+/// Permissions and modified times follow the source, even for files whose
+/// contents already match.
+fn test_file_metadata() {
+    use std::{
+        os::unix::fs::PermissionsExt,
+        time::{Duration, SystemTime},
+    };
+
+    let context = common::TestContext::new();
+
+    context.write_file("in/run.sh", 1024);
+    context.write_file("in/readonly.bin", 1048576); // 1MB
+    fs::copy(context.path("in/run.sh"), context.path("out/run.sh")).unwrap();
+
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    fs::File::options()
+        .write(true)
+        .open(context.path("in/run.sh"))
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+
+    let set_mode = |path: &str, mode: u32| {
+        fs::set_permissions(context.path(path), fs::Permissions::from_mode(mode)).unwrap();
+    };
+    set_mode("in/run.sh", 0o755);
+    set_mode("in/readonly.bin", 0o444);
+
+    let mode = |path: &str| {
+        fs::metadata(context.path(path))
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777
+    };
+
+    // Sync twice so the read-only file has to be updated in place.
+    binsync::sync(&context.path("in"), &context.path("out")).unwrap();
+    context.write_file("in/readonly.bin", 1048576); // 1MB
+    set_mode("in/readonly.bin", 0o444);
+    binsync::sync(&context.path("in"), &context.path("out")).unwrap();
+
+    assert_eq!(0o755, mode("out/run.sh"));
+    assert_eq!(0o444, mode("out/readonly.bin"));
+    assert!(context.compare_hashes("in/readonly.bin", "out/readonly.bin"));
+
+    let modified = fs::metadata(context.path("out/run.sh"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    assert_eq!(mtime, modified);
+    // Special permission bits in a manifest are never applied.
+    let mut manifest = Manifest::from_path(context.path("in")).unwrap();
+    for file in &mut manifest.files {
+        file.metadata.mode = Some(0o4755);
+    }
+
+    let provider = CachingChunkProvider::new(context.path("in"));
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    syncer.sync().unwrap();
+
+    let mode = fs::metadata(context.path("out/run.sh"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(0o755, mode & 0o7777);
+
+    // Times the platform can not represent fail the sync instead of panicking.
+    let mut manifest = Manifest::from_path(context.path("in")).unwrap();
+    manifest.files[0].metadata.modified = Some(binsync::Timestamp {
+        seconds: i64::MAX,
+        nanos: u32::MAX,
+    });
+
+    let provider = CachingChunkProvider::new(context.path("in"));
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    assert!(matches!(
+        syncer.sync(),
+        Err(BinsyncError::InvalidManifest(_))
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+/// Symbolic and hard links are recreated in the destination unless the
+/// manifest was generated following links.
+fn test_links() {
+    use std::os::unix::fs::{symlink, MetadataExt};
+
+    let context = common::TestContext::new();
+
+    context.write_file("in/lib/libfoo.so", 1048576); // 1MB
+    context.write_file("in/data.bin", 1024);
+    symlink("lib", context.path("in/lib64")).unwrap();
+    fs::hard_link(context.path("in/data.bin"), context.path("in/data2.bin")).unwrap();
+
+    let from = context.path("in");
+    let manifest = Manifest::from_path(&from).unwrap();
+    assert_eq!(2, manifest.files.len());
+    assert_eq!(2, manifest.links.len());
+    assert_eq!(LinkKind::Symlink("lib".into()), manifest.links[1].kind);
+
+    // A directory with contents where a link belongs is only replaced when
+    // the delete policy lets its contents go.
+    context.write_file("out/lib64/old/libfoo.so", 1024);
+
+    let provider = CachingChunkProvider::new(&from);
+    let syncer = Syncer::new(
+        context.path("out"),
+        provider,
+        Manifest::from_path(&from).unwrap(),
+    );
+    assert!(matches!(syncer.plan(), Err(BinsyncError::LinkBlocked(_))));
+
+    let provider = CachingChunkProvider::new(&from);
+    let mut syncer = Syncer::new(
+        context.path("out"),
+        provider,
+        Manifest::from_path(&from).unwrap(),
+    );
+    syncer.delete_policy(DeletePolicy::Delete);
+    syncer.protect("lib64/old");
+    assert!(matches!(syncer.plan(), Err(BinsyncError::LinkBlocked(_))));
+    assert!(Path::new(&context.path("out/lib64/old/libfoo.so")).exists());
+
+    let provider = CachingChunkProvider::new(&from);
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    syncer.delete_policy(DeletePolicy::Quarantine(context.path("quarantine").into()));
+    syncer.sync().unwrap();
+    assert!(Path::new(&context.path("quarantine/lib64/old/libfoo.so")).exists());
+
+    let link = fs::read_link(context.path("out/lib64")).unwrap();
+    assert_eq!(Path::new("lib"), link);
+
+    let data = fs::metadata(context.path("out/data.bin")).unwrap();
+    let data2 = fs::metadata(context.path("out/data2.bin")).unwrap();
+    assert_eq!(data.ino(), data2.ino());
+    assert!(context.compare_hashes("in/data.bin", "out/data2.bin"));
+
+    let options = ManifestOptions {
+        follow_links: true,
+        ..Default::default()
+    };
+    let manifest = Manifest::from_path_with_options(&from, &options).unwrap();
+    assert_eq!(4, manifest.files.len());
+    assert!(manifest.links.is_empty());
+}
+
+#[test]
+/// Manifests round trip through bytes, and manifests written before chunk ids
+/// were widened can still be read and synced.
+fn test_manifest_versions() {
+    use std::convert::TryInto;
+
+    #[derive(serde::Serialize)]
+    struct LegacyChunk {
+        hash: u64,
+        offset: u64,
+        length: u64,
+    }
+
+    #[derive(serde::Serialize)]
+    struct LegacyFileChunkInfo {
+        path: std::path::PathBuf,
+        chunks: Vec<LegacyChunk>,
+    }
+
+    let context = common::TestContext::new();
+
+    context.write_file("in/test.bin", 1048576); // 1MB
+    fs::copy(context.path("in/test.bin"), context.path("out/test.bin")).unwrap();
+
+    let from = context.path("in");
+    let manifest = binsync::generate_manifest(&from).unwrap();
+    assert_eq!(HashAlgorithm::Blake3, manifest.header.hash);
+    assert_eq!(
+        manifest,
+        Manifest::from_bytes(&manifest.to_bytes().unwrap()).unwrap()
+    );
+
+    // Rebuild the manifest the way the first release of the crate wrote it.
+    let contents = fs::read(context.path("in/test.bin")).unwrap();
+    let legacy = vec![LegacyFileChunkInfo {
+        path: "test.bin".into(),
+        chunks: manifest.files[0]
+            .chunks
+            .iter()
+            .map(|chunk| {
+                let start = chunk.offset as usize;
+                let end = start + chunk.length as usize;
+                let digest = md5::compute(&contents[start..end]);
+
+                LegacyChunk {
+                    hash: u64::from_le_bytes(digest[0..8].try_into().unwrap()),
+                    offset: chunk.offset,
+                    length: chunk.length,
+                }
+            })
+            .collect(),
+    }];
+
+    let legacy = Manifest::from_bytes(&bincode::serialize(&legacy).unwrap()).unwrap();
+    assert_eq!(HashAlgorithm::Md5Truncated, legacy.header.hash);
+
+    let provider = CachingChunkProvider::new(&from);
+    let mut syncer = Syncer::new(context.path("out"), provider, legacy);
+
+    let plan = syncer.plan().unwrap();
+    assert_eq!(0, plan.operations.len());
+
+    fs::remove_file(context.path("out/test.bin")).unwrap();
+    syncer.sync().unwrap();
+
+    assert!(context.compare_hashes("in/test.bin", "out/test.bin"));
+}
+
+#[test]
+/// Files record their length and digest, and a written file that does not
+/// match them fails the sync.
+fn test_verify_files() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/test.bin", 1048577); // 1MB
+
+    let from = context.path("in");
+    let mut manifest = binsync::generate_manifest(&from).unwrap();
+
+    let contents = fs::read(context.path("in/test.bin")).unwrap();
+    assert_eq!(1048577, manifest.files[0].length);
+    assert_eq!(
+        Some(HashAlgorithm::Blake3.digest(&contents)),
+        manifest.files[0].digest
+    );
+
+    manifest.files[0].digest = Some(Digest([0; 32]));
+
+    let provider = CachingChunkProvider::new(&from);
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+
+    match syncer.sync() {
+        Err(BinsyncError::VerificationFailed(_)) => {}
+        other => panic!("Expected verification to fail, got {:?}", other),
+    }
+}
+
+#[test]
+/// The header records how the manifest was chunked and manifests that cannot
+/// be handled are rejected before syncing.
+fn test_manifest_header() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/test.bin", 1024);
+
+    let from = context.path("in");
+    let manifest = binsync::generate_manifest(&from).unwrap();
+    assert_eq!(binsync::FORMAT_VERSION, manifest.header.version);
+    assert_eq!(ChunkingAlgorithm::FastCdc, manifest.header.chunking);
+    assert_eq!(ChunkerConfig::default(), manifest.header.chunker);
+
+    let mut future = binsync::generate_manifest(&from).unwrap();
+    future.header.version = 99;
+
+    match Manifest::from_bytes(&future.to_bytes().unwrap()) {
+        Err(BinsyncError::UnsupportedVersion(99)) => {}
+        other => panic!("Expected an unsupported version, got {:?}", other),
+    }
+
+    let provider = CachingChunkProvider::new(&from);
+    let syncer = Syncer::new(context.path("out"), provider, future);
+    assert!(matches!(
+        syncer.plan(),
+        Err(BinsyncError::UnsupportedVersion(99))
+    ));
+
+    let mut invalid = manifest;
+    invalid.header.chunker.max_size = invalid.header.chunker.min_size - 1;
+
+    let provider = CachingChunkProvider::new(&from);
+    let syncer = Syncer::new(context.path("out"), provider, invalid);
+    assert!(matches!(
+        syncer.plan(),
+        Err(BinsyncError::InvalidChunkerConfig { .. })
+    ));
+}
+
+#[test]
+/// Manifests generated with custom chunk sizes sync using those sizes.
+fn test_chunker_config() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/test.bin", 1048576); // 1MB
+    fs::copy(context.path("in/test.bin"), context.path("out/test.bin")).unwrap();
+
+    assert!(ChunkerConfig::new(4096, 1024, 16384).is_err());
+
+    let options = ManifestOptions {
+        chunker: ChunkerConfig::new(4096, 8192, 16384).unwrap(),
+        ..Default::default()
+    };
+
+    let from = context.path("in");
+    let manifest = Manifest::from_path_with_options(&from, &options).unwrap();
+    assert_eq!(options.chunker, manifest.header.chunker);
+    assert!(manifest.files[0].chunks.iter().all(|c| c.length <= 16384));
+    assert!(manifest.files[0].chunks.len() > 64);
+
+    let provider = CachingChunkProvider::new(&from);
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+
+    // The destination is chunked with the same sizes so nothing changes.
+    let plan = syncer.plan().unwrap();
+    assert_eq!(0, plan.operations.len());
+
+    context.write_file("out/test.bin", 1048576); // 1MB
+    syncer.sync().unwrap();
+
+    assert!(context.compare_hashes("in/test.bin", "out/test.bin"));
+}
+
+#[test]
+/// Files much larger than the generation buffer are chunked the same way as
+/// the syncer chunks the whole destination file.
+fn test_streaming_generation() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/test.bin", 5242883); // 5MB
+    fs::copy(context.path("in/test.bin"), context.path("out/test.bin")).unwrap();
+
+    let from = context.path("in");
+    let manifest = binsync::generate_manifest(&from).unwrap();
+
+    let mut offset = 0;
+    for chunk in &manifest.files[0].chunks {
+        assert_eq!(offset, chunk.offset);
+        offset += chunk.length;
+    }
+    assert_eq!(5242883, offset);
+
+    let provider = CachingChunkProvider::new(&from);
+    let syncer = Syncer::new(context.path("out"), provider, manifest);
+
+    let plan = syncer.plan().unwrap();
+    assert_eq!(0, plan.operations.len());
+}
+
+#[test]
+/// Planning with a tiny memory limit gives the same plan as the default.
+fn test_plan_memory_limit() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/test.bin", 2097152); // 2MB
+    fs::copy(context.path("in/test.bin"), context.path("out/test.bin")).unwrap();
+
+    // Move the second half of the source to the front.
+    let mut data = fs::read(context.path("in/test.bin")).unwrap();
+    let mut back = data.split_off(data.len() / 2);
+    back.append(&mut data);
+    fs::write(context.path("in/test.bin"), back).unwrap();
+
+    let from = context.path("in");
+    let plan_with_limit = |limit: Option<usize>| {
+        let manifest = binsync::generate_manifest(&from).unwrap();
+        let provider = CachingChunkProvider::new(&from);
+        let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+        if let Some(limit) = limit {
+            syncer.memory_limit(limit);
+        }
+
+        let plan = syncer.plan().unwrap();
+        (plan.total_ops, plan.get_fetch_size(), syncer, plan)
+    };
+
+    let (total_ops, fetch_size, _, _) = plan_with_limit(None);
+    let (limited_ops, limited_fetch_size, mut syncer, plan) = plan_with_limit(Some(0));
+
+    assert_eq!(total_ops, limited_ops);
+    assert_eq!(fetch_size, limited_fetch_size);
+    assert!(fetch_size < 2097152 / 4);
+
+    syncer.sync_from_plan(&plan).unwrap();
+
+    assert!(context.compare_hashes("in/test.bin", "out/test.bin"));
+}
+
+#[cfg(unix)]
+#[test]
+/// Unreadable files fail generation with their path, or are skipped and
+/// reported when asked to.
+fn test_generation_errors() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/test.bin", 1024);
+    fs::create_dir_all(context.path("in/nested")).unwrap();
+    std::os::unix::fs::symlink("missing.bin", context.path("in/nested/broken.bin")).unwrap();
+
+    let from = context.path("in");
+    let options = ManifestOptions {
+        follow_links: true,
+        ..Default::default()
+    };
+
+    match Manifest::from_path_with_options(&from, &options) {
+        Err(BinsyncError::ReadFailed { path, .. }) => {
+            assert_eq!(Path::new(&from).join("nested/broken.bin"), path)
+        }
+        other => panic!("expected a read failure, got {:?}", other),
+    }
+
+    let (manifest, skipped) = Manifest::from_path_skipping_errors(&from, &options).unwrap();
+    assert_eq!(1, manifest.files.len());
+    assert_eq!(Path::new("test.bin"), manifest.files[0].path);
+    assert_eq!(1, skipped.len());
+    assert_eq!(Path::new(&from).join("nested/broken.bin"), skipped[0].path);
+
+    assert!(matches!(
+        Manifest::from_path(context.path("missing")),
+        Err(BinsyncError::ReadFailed { .. })
+    ));
+}
+
+#[test]
+/// Include and exclude patterns decide which files are recorded.
+fn test_glob_filters() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/game.exe", 1024);
+    context.write_file("in/game.pdb", 1024);
+    context.write_file("in/data/level.pak", 1024);
+    context.write_file("in/data/level.tmp", 1024);
+    context.write_file("in/data/debug/symbols.pdb", 1024);
+    context.write_file("in/.git/HEAD", 1024);
+    fs::create_dir_all(context.path("in/.git/refs")).unwrap();
+    fs::create_dir_all(context.path("in/logs")).unwrap();
+
+    let from = context.path("in");
+    let options = ManifestOptions {
+        exclude: vec!["**/*.pdb".into(), ".git/**".into(), "*.tmp".into()],
+        ..Default::default()
+    };
+    let manifest = Manifest::from_path_with_options(&from, &options).unwrap();
+
+    let files: Vec<_> = manifest.files.iter().map(|f| f.path.clone()).collect();
+    assert_eq!(
+        vec![Path::new("data/level.pak"), Path::new("game.exe")],
+        files
+    );
+    let directories: Vec<_> = manifest
+        .directories
+        .iter()
+        .map(|d| d.path.clone())
+        .collect();
+    assert_eq!(
+        vec![
+            Path::new("data"),
+            Path::new("data/debug"),
+            Path::new("logs")
+        ],
+        directories
+    );
+
+    let options = ManifestOptions {
+        include: vec!["data/**".into()],
+        exclude: vec!["*.tmp".into()],
+        ..Default::default()
+    };
+    let manifest = Manifest::from_path_with_options(&from, &options).unwrap();
+
+    let files: Vec<_> = manifest.files.iter().map(|f| f.path.clone()).collect();
+    assert_eq!(
+        vec![
+            Path::new("data/debug/symbols.pdb"),
+            Path::new("data/level.pak")
+        ],
+        files
+    );
+
+    let options = ManifestOptions {
+        exclude: vec!["[".into()],
+        ..Default::default()
+    };
+    assert!(matches!(
+        Manifest::from_path_with_options(&from, &options),
+        Err(BinsyncError::InvalidPattern(_))
+    ));
+}
+
+#[test]
+/// `.binsyncignore` files follow gitignore rules for their own directory and
+/// everything below it.
+fn test_ignore_files() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/game.exe", 1024);
+    context.write_file("in/cover.psd", 1024);
+    context.write_file("in/keep.psd", 1024);
+    context.write_file("in/build/out.obj", 1024);
+    context.write_file("in/art/hero.psd", 1024);
+    context.write_file("in/art/hero.png", 1024);
+    context.write_file("in/art/raw/hero.tga", 1024);
+    context.write_file("in/audio/raw/theme.wav", 1024);
+    fs::write(
+        context.path("in/.binsyncignore"),
+        "*.psd\n!keep.psd\nbuild/\n",
+    )
+    .unwrap();
+    fs::write(context.path("in/art/.binsyncignore"), "!*.psd\nraw/\n").unwrap();
+
+    let from = context.path("in");
+    let manifest = Manifest::from_path(&from).unwrap();
+
+    let files: Vec<_> = manifest.files.iter().map(|f| f.path.clone()).collect();
+    assert_eq!(
+        vec![
+            Path::new("art/hero.png"),
+            Path::new("art/hero.psd"),
+            Path::new("audio/raw/theme.wav"),
+            Path::new("game.exe"),
+            Path::new("keep.psd"),
+        ],
+        files
+    );
+
+    let options = ManifestOptions {
+        ignore_files: false,
+        ..Default::default()
+    };
+    let manifest = Manifest::from_path_with_options(&from, &options).unwrap();
+    assert_eq!(10, manifest.files.len());
+
+    fs::write(context.path("in/art/.binsyncignore"), "[\n").unwrap();
+    assert!(matches!(
+        Manifest::from_path(&from),
+        Err(BinsyncError::InvalidIgnoreFile { .. })
+    ));
+
+    // When skipping errors only the directory of the broken file is left out.
+    let (manifest, skipped) =
+        Manifest::from_path_skipping_errors(&from, &ManifestOptions::default()).unwrap();
+    assert_eq!(3, manifest.files.len());
+    assert!(!manifest.files.iter().any(|f| f.path.starts_with("art")));
+    assert_eq!(1, skipped.len());
+    assert_eq!(Path::new(&from).join("art/.binsyncignore"), skipped[0].path);
+}
+
+#[test]
+/// Updating a manifest only chunks files that changed and matches a full
+/// rebuild.
+fn test_update_manifest() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/same.bin", 300000);
+    context.write_file("in/changed.bin", 300000);
+    context.write_file("in/removed.bin", 300000);
+
+    let from = context.path("in");
+    let previous = Manifest::from_path(&from).unwrap();
+
+    context.write_file("in/changed.bin", 400000);
+    context.write_file("in/added.bin", 300000);
+    fs::remove_file(context.path("in/removed.bin")).unwrap();
+
+    let updated = Manifest::update_from_path(&previous, &from).unwrap();
+    assert_eq!(Manifest::from_path(&from).unwrap(), updated);
+
+    // Files with the same size and modification time are trusted to be
+    // unchanged, which shows their chunks are reused.
+    let path = context.path("in/same.bin");
+    let modified = fs::metadata(&path).unwrap().modified().unwrap();
+    let mut data = fs::read(&path).unwrap();
+    data.reverse();
+    fs::write(&path, data).unwrap();
+    fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+
+    let reused = Manifest::update_from_path(&updated, &from).unwrap();
+    assert_eq!(updated.files, reused.files);
+
+    // Replacing the file changes its inode, which can be checked as well.
+    if cfg!(unix) {
+        fs::copy(&path, context.path("in/copy.tmp")).unwrap();
+        fs::rename(context.path("in/copy.tmp"), &path).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let options = ManifestOptions {
+            compare_inodes: true,
+            ..Default::default()
+        };
+        let rebuilt = Manifest::update_from_path_with_options(&updated, &from, &options).unwrap();
+        assert_eq!(Manifest::from_path(&from).unwrap(), rebuilt);
+        assert_ne!(updated.files, rebuilt.files);
+    }
+}
+
+#[test]
+/// Diffing manifests reports changed files and how many chunks are new.
+fn test_manifest_diff() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/same.bin", 300000);
+    context.write_file("in/grown.bin", 1048576);
+    context.write_file("in/moved.bin", 300000);
+    context.write_file("in/removed.bin", 300000);
+
+    let from = context.path("in");
+    let old = Manifest::from_path(&from).unwrap();
+    assert!(old.diff(&old).is_empty());
+
+    let mut data = fs::read(context.path("in/grown.bin")).unwrap();
+    data.extend_from_slice(&fs::read(context.path("in/same.bin")).unwrap());
+    fs::write(context.path("in/grown.bin"), data).unwrap();
+    fs::rename(context.path("in/moved.bin"), context.path("in/renamed.bin")).unwrap();
+    fs::remove_file(context.path("in/removed.bin")).unwrap();
+    context.write_file("in/added.bin", 300000);
+
+    let new = Manifest::from_path(&from).unwrap();
+    let diff = old.diff(&new);
+
+    assert_eq!(1, diff.added.len());
+    assert_eq!(Path::new("added.bin"), diff.added[0].path);
+    assert_eq!(0, diff.added[0].reused_chunks);
+    assert_eq!(300000, diff.added[0].new_bytes);
+
+    assert_eq!(vec![Path::new("removed.bin")], diff.removed);
+
+    assert_eq!(1, diff.renamed.len());
+    assert_eq!(Path::new("moved.bin"), diff.renamed[0].from);
+    assert_eq!(Path::new("renamed.bin"), diff.renamed[0].to);
+
+    assert_eq!(1, diff.modified.len());
+    let grown = &diff.modified[0];
+    assert_eq!(Path::new("grown.bin"), grown.path);
+    assert!(grown.reused_chunks > 0);
+    assert!(grown.new_chunks > 0);
+    assert!(grown.new_bytes < 300000 + 2 * 131072);
+}
+
+#[test]
+/// Manifests round trip through JSON with digests written as hex strings.
+fn test_manifest_json() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/test.bin", 300000);
+    context.write_file("in/nested/other.bin", 1024);
+
+    let from = context.path("in");
+    let manifest = Manifest::from_path(&from).unwrap();
+
+    let json = manifest.to_json().unwrap();
+    let digest = manifest.files[0].chunks[0].hash.to_string();
+    assert!(json.contains(&format!("\"{}\"", digest)));
+    assert_eq!(
+        digest.parse::<Digest>().unwrap(),
+        manifest.files[0].chunks[0].hash
+    );
+    assert_eq!(manifest, Manifest::from_json(&json).unwrap());
+
+    // The binary format is unaffected by how JSON writes digests.
+    assert_eq!(
+        manifest,
+        Manifest::from_bytes(&manifest.to_bytes().unwrap()).unwrap()
+    );
+
+    let mut future = Manifest::from_path(&from).unwrap();
+    future.header.version = 99;
+    assert!(matches!(
+        Manifest::from_json(&future.to_json().unwrap()),
+        Err(BinsyncError::UnsupportedVersion(99))
+    ));
+
+    assert!(matches!(
+        Manifest::from_json("{}"),
+        Err(BinsyncError::UnsupportedVersion(0))
+    ));
+    assert!(matches!(
+        "not a digest".parse::<Digest>(),
+        Err(BinsyncError::InvalidDigest(_))
+    ));
+
+    #[cfg(feature = "cbor")]
+    assert_eq!(
+        manifest,
+        Manifest::from_cbor(&manifest.to_cbor().unwrap()).unwrap()
+    );
+}
+
+#[test]
+/// Compressed manifests are smaller and read the same way as plain ones.
+fn test_compressed_manifest() {
+    let context = common::TestContext::new();
+
+    for i in 0..50 {
+        context.write_file(&format!("in/assets/textures/texture_{}.bin", i), 1024);
+    }
+
+    let from = context.path("in");
+    let manifest = Manifest::from_path(&from).unwrap();
+
+    let plain = manifest.to_bytes().unwrap();
+    let compressed = manifest.to_compressed_bytes().unwrap();
+    assert!(compressed.len() < plain.len());
+
+    assert_eq!(manifest, Manifest::from_bytes(&plain).unwrap());
+    assert_eq!(manifest, Manifest::from_bytes(&compressed).unwrap());
+
+    let mut corrupt = compressed;
+    corrupt.truncate(corrupt.len() / 2);
+    assert!(Manifest::from_bytes(&corrupt).is_err());
+}
+
+#[test]
+/// Only manifests signed by a trusted key are synced.
+fn test_signed_manifest() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/test.bin", 300000);
+
+    let from = context.path("in");
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let mut keys = TrustedKeys::new();
+    keys.add(key.verifying_key());
+
+    let mut manifest = Manifest::from_path(&from).unwrap();
+    assert!(matches!(
+        manifest.verify_signature(&keys),
+        Err(BinsyncError::MissingSignature)
+    ));
+
+    manifest.sign(&key).unwrap();
+    manifest.verify_signature(&keys).unwrap();
+
+    // The signature survives serialization.
+    let manifest = Manifest::from_bytes(&manifest.to_bytes().unwrap()).unwrap();
+    manifest.verify_signature(&keys).unwrap();
+    let manifest = Manifest::from_json(&manifest.to_json().unwrap()).unwrap();
+    manifest.verify_signature(&keys).unwrap();
+
+    let other_key = SigningKey::from_bytes(&[8; 32]).verifying_key();
+    let other_hex: String = other_key
+        .as_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let mut other_keys = TrustedKeys::new();
+    other_keys.add_hex(&other_hex).unwrap();
+    assert!(matches!(
+        other_keys.add_hex("not a key"),
+        Err(BinsyncError::InvalidKey(_))
+    ));
+    assert!(matches!(
+        manifest.verify_signature(&other_keys),
+        Err(BinsyncError::InvalidSignature)
+    ));
+
+    let mut tampered = Manifest::from_bytes(&manifest.to_bytes().unwrap()).unwrap();
+    tampered.files[0].path = "../escape.bin".into();
+    assert!(matches!(
+        tampered.verify_signature(&keys),
+        Err(BinsyncError::InvalidSignature)
+    ));
+
+    let provider = CachingChunkProvider::new(&from);
+    let mut syncer = Syncer::new(context.path("out"), provider, tampered);
+    syncer.trusted_keys(keys.clone());
+    assert!(matches!(syncer.sync(), Err(BinsyncError::InvalidSignature)));
+    assert!(!Path::new(&context.path("out/test.bin")).exists());
+
+    let provider = CachingChunkProvider::new(&from);
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    syncer.trusted_keys(keys);
+    syncer.sync().unwrap();
+    assert!(context.compare_hashes("in/test.bin", "out/test.bin"));
+}
+
+#[test]
+/// Layers are synced in one pass with later layers replacing earlier ones.
+fn test_layered_manifest() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/base/game.bin", 300000);
+    context.write_file("in/base/shared.bin", 300000);
+    context.write_file("in/dlc/shared.bin", 200000);
+    context.write_file("in/dlc/dlc/extra.bin", 100000);
+
+    let base = Manifest::from_path(context.path("in/base")).unwrap();
+    let dlc = Manifest::from_path(context.path("in/dlc")).unwrap();
+
+    let mut layers = LayeredManifest::new("base", base);
+    layers.push("dlc", dlc).unwrap();
+    assert_eq!(Some("dlc"), layers.owner("shared.bin"));
+    assert_eq!(Some("base"), layers.owner("game.bin"));
+
+    let duplicate = Manifest::from_path(context.path("in/dlc")).unwrap();
+    assert!(matches!(
+        layers.push("dlc", duplicate),
+        Err(BinsyncError::InvalidLayer(_))
+    ));
+
+    let mut provider = CachingChunkProvider::new(context.path("in/base"));
+    provider.layer_source("dlc", context.path("in/dlc"));
+    let mut syncer = Syncer::with_layers(context.path("out"), provider, layers);
+    syncer.delete_policy(DeletePolicy::Delete);
+
+    let plan = syncer.plan().unwrap();
+    assert_eq!("dlc", plan.owners[Path::new("dlc/extra.bin")]);
+    syncer.sync_from_plan(&plan).unwrap();
+
+    assert!(context.compare_hashes("in/base/game.bin", "out/game.bin"));
+    assert!(context.compare_hashes("in/dlc/shared.bin", "out/shared.bin"));
+    assert!(context.compare_hashes("in/dlc/dlc/extra.bin", "out/dlc/extra.bin"));
+
+    // A held layer keeps whatever is installed for its paths while the rest
+    // is synced and cleaned up around it.
+    context.write_file("out/dlc/extra.bin", 5000);
+    context.write_file("out/shared.bin", 5000);
+    context.write_file("out/game.bin", 5000);
+    context.write_file("out/stray.bin", 5000);
+    let extra = fs::read(context.path("out/dlc/extra.bin")).unwrap();
+    let shared = fs::read(context.path("out/shared.bin")).unwrap();
+
+    let mut layers = LayeredManifest::new(
+        "base",
+        Manifest::from_path(context.path("in/base")).unwrap(),
+    );
+    layers
+        .push("dlc", Manifest::from_path(context.path("in/dlc")).unwrap())
+        .unwrap();
+
+    let provider = CachingChunkProvider::new(context.path("in/base"));
+    let mut syncer = Syncer::with_layers(context.path("out"), provider, layers);
+    syncer.delete_policy(DeletePolicy::Delete);
+    syncer.hold_layer("dlc");
+    syncer.sync().unwrap();
+
+    assert!(context.compare_hashes("in/base/game.bin", "out/game.bin"));
+    assert_eq!(extra, fs::read(context.path("out/dlc/extra.bin")).unwrap());
+    assert_eq!(shared, fs::read(context.path("out/shared.bin")).unwrap());
+    assert!(!Path::new(&context.path("out/stray.bin")).exists());
+
+    // Dropping the overlay brings back the base version of shared files and
+    // only removes what the overlay added.
+    let base = Manifest::from_path(context.path("in/base")).unwrap();
+    let provider = CachingChunkProvider::new(context.path("in/base"));
+    let mut syncer = Syncer::with_layers(
+        context.path("out"),
+        provider,
+        LayeredManifest::new("base", base),
+    );
+    syncer.delete_policy(DeletePolicy::Delete);
+    syncer.sync().unwrap();
+
+    assert!(context.compare_hashes("in/base/game.bin", "out/game.bin"));
+    assert!(context.compare_hashes("in/base/shared.bin", "out/shared.bin"));
+    assert!(!Path::new(&context.path("out/dlc")).exists());
+}
+
+#[test]
+/// Stats count shared chunks once and bucket every chunk by size.
+fn test_manifest_stats() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/a.bin", 300000);
+    context.write_file("in/c.bin", 100000);
+    fs::copy(context.path("in/a.bin"), context.path("in/b.bin")).unwrap();
+
+    let manifest = Manifest::from_path(context.path("in")).unwrap();
+    let stats = manifest.stats();
+
+    assert_eq!(3, stats.file_count);
+    assert_eq!(700000, stats.total_bytes);
+    assert_eq!(400000, stats.unique_bytes);
+    assert!((stats.dedup_ratio - 1.75).abs() < f64::EPSILON);
+
+    let histogram: usize = stats.histogram.iter().map(|b| b.chunks).sum();
+    assert_eq!(stats.chunk_count, histogram);
+    assert_eq!(
+        manifest.header.chunker.max_size as u64,
+        stats.histogram.last().unwrap().max
+    );
+
+    assert_eq!(
+        (PathBuf::from("a.bin"), 300000),
+        stats.largest_files[0].clone()
+    );
+
+    let shared: Vec<&Path> = stats.most_shared.iter().map(|f| f.path.as_path()).collect();
+    assert_eq!(vec![Path::new("a.bin"), Path::new("b.bin")], shared);
+    assert_eq!(300000, stats.most_shared[0].shared_bytes);
+
+    // A hand built header that is out of order still gets ordered buckets.
+    let mut manifest = manifest;
+    manifest.header.chunker = ChunkerConfig {
+        min_size: 200000,
+        avg_size: 100000,
+        max_size: 50000,
+    };
+    let stats = manifest.stats();
+    let histogram: usize = stats.histogram.iter().map(|b| b.chunks).sum();
+    assert_eq!(stats.chunk_count, histogram);
+    assert!(stats.histogram.windows(2).all(|b| b[0].max < b[1].min));
+}
+
+#[cfg(unix)]
+#[test]
+/// Names that are not valid Unicode round trip and colliding names are
+/// rejected.
+fn test_portable_paths() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let context = common::TestContext::new();
+
+    let latin1 = OsStr::from_bytes(b"caf\xe9.bin");
+    let input = Path::new(&context.path("in")).to_path_buf();
+    fs::write(input.join(latin1), b"latin1").unwrap();
+    fs::write(input.join("back\\slash%.bin"), b"escaped").unwrap();
+    context.write_file("in/dir/file.bin", 1000);
+
+    let manifest = Manifest::from_path(context.path("in")).unwrap();
+    assert!(manifest.files.iter().any(|f| f.path == Path::new(latin1)));
+
+    let json = manifest.to_json().unwrap();
+    assert!(json.contains("\"caf%E9.bin\""));
+    assert!(json.contains("\"back%5Cslash%25.bin\""));
+    assert!(json.contains("\"dir/file.bin\""));
+    assert_eq!(manifest, Manifest::from_json(&json).unwrap());
+    assert_eq!(
+        manifest,
+        Manifest::from_bytes(&manifest.to_bytes().unwrap()).unwrap()
+    );
+
+    let provider = CachingChunkProvider::new(context.path("in"));
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    syncer.sync().unwrap();
+
+    let output = Path::new(&context.path("out")).to_path_buf();
+    assert_eq!(b"latin1".to_vec(), fs::read(output.join(latin1)).unwrap());
+    assert_eq!(
+        b"escaped".to_vec(),
+        fs::read(output.join("back\\slash%.bin")).unwrap()
+    );
+
+    // Names that collide on Windows or macOS are rejected unless allowed.
+    context.write_file("in/README.txt", 10);
+    context.write_file("in/Readme.txt", 10);
+    context.write_file("in/caf\u{e9}.txt", 10);
+    context.write_file("in/cafe\u{301}.txt", 10);
+
+    assert!(matches!(
+        Manifest::from_path(context.path("in")),
+        Err(BinsyncError::PathCollision { .. })
+    ));
+
+    let options = ManifestOptions {
+        allow_path_collisions: true,
+        ..Default::default()
+    };
+    let manifest = Manifest::from_path_with_options(context.path("in"), &options).unwrap();
+    let collisions = manifest.path_collisions();
+    assert_eq!(2, collisions.len());
+    assert!(collisions.iter().any(|c| c.kind == CollisionKind::Case
+        && c.first == Path::new("README.txt")
+        && c.second == Path::new("Readme.txt")));
+    assert!(collisions
+        .iter()
+        .any(|c| c.kind == CollisionKind::Normalization));
+}
+
+#[test]
+/// Paths in a manifest can not lead outside the destination.
+fn test_unsafe_paths() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/test.bin", 1024);
+
+    let unsafe_paths = [
+        "../escape.bin",
+        "nested/../../escape.bin",
+        "/tmp/escape.bin",
+    ];
+
+    for path in unsafe_paths {
+        let mut manifest = Manifest::from_path(context.path("in")).unwrap();
+        manifest.files[0].path = PathBuf::from(path);
+
+        let provider = CachingChunkProvider::new(context.path("in"));
+        let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+        assert!(matches!(syncer.sync(), Err(BinsyncError::UnsafePath(_))));
+    }
+
+    assert!(!Path::new(&context.path("escape.bin")).exists());
+}
+
+#[cfg(unix)]
+#[test]
+/// Links can not lead writes outside the destination, in the manifest or
+/// already on disk.
+fn test_unsafe_links() {
+    use std::os::unix::fs::symlink;
+
+    let context = common::TestContext::new();
+
+    context.write_file("in/dir/test.bin", 1024);
+    fs::create_dir_all(context.path("elsewhere")).unwrap();
+
+    // Links in the manifest may point outside of the destination, like one to
+    // a system library, but nothing in the manifest can be below them.
+    symlink("../../elsewhere", context.path("in/dir/up")).unwrap();
+    symlink("/usr/lib", context.path("in/lib")).unwrap();
+    let manifest = Manifest::from_path(context.path("in")).unwrap();
+    let provider = CachingChunkProvider::new(context.path("in"));
+    let syncer = Syncer::new(context.path("out"), provider, manifest);
+    assert!(syncer.plan().is_ok());
+
+    for below in ["dir/up/sub", "lib/sub"] {
+        let mut manifest = Manifest::from_path(context.path("in")).unwrap();
+        manifest
+            .directories
+            .push(DirectoryInfo { path: below.into() });
+        let provider = CachingChunkProvider::new(context.path("in"));
+        let syncer = Syncer::new(context.path("out"), provider, manifest);
+        assert!(matches!(syncer.plan(), Err(BinsyncError::UnsafePath(_))));
+    }
+    fs::remove_file(context.path("in/dir/up")).unwrap();
+    fs::remove_file(context.path("in/lib")).unwrap();
+
+    // Going up from a link leaves from where it points, not from its text.
+    symlink(".", context.path("in/here")).unwrap();
+    symlink("here/..", context.path("in/up")).unwrap();
+    let mut manifest = Manifest::from_path(context.path("in")).unwrap();
+    manifest.directories.push(DirectoryInfo {
+        path: "up/sub".into(),
+    });
+    let provider = CachingChunkProvider::new(context.path("in"));
+    let syncer = Syncer::new(context.path("out"), provider, manifest);
+    assert!(matches!(syncer.plan(), Err(BinsyncError::UnsafePath(_))));
+    fs::remove_file(context.path("in/up")).unwrap();
+
+    symlink("dir", context.path("in/there")).unwrap();
+    symlink("there/..", context.path("in/up")).unwrap();
+    let mut manifest = Manifest::from_path(context.path("in")).unwrap();
+    manifest.directories.push(DirectoryInfo {
+        path: "up/sub".into(),
+    });
+    let provider = CachingChunkProvider::new(context.path("in"));
+    let syncer = Syncer::new(context.path("out"), provider, manifest);
+    assert!(syncer.plan().is_ok());
+    for link in ["here", "there", "up"] {
+        fs::remove_file(context.path(&format!("in/{}", link))).unwrap();
+    }
+
+    // Nor can files be written through a link already in the destination.
+    let elsewhere = fs::canonicalize(context.path("elsewhere")).unwrap();
+    symlink(&elsewhere, context.path("out/dir")).unwrap();
+    let manifest = Manifest::from_path(context.path("in")).unwrap();
+    let provider = CachingChunkProvider::new(context.path("in"));
+    let syncer = Syncer::new(context.path("out"), provider, manifest);
+    assert!(matches!(syncer.plan(), Err(BinsyncError::UnsafePath(_))));
+    assert!(!elsewhere.join("test.bin").exists());
+
+    // Links that stay inside the destination are fine.
+    fs::remove_file(context.path("out/dir")).unwrap();
+    fs::create_dir_all(context.path("out/real")).unwrap();
+    symlink("real", context.path("out/dir")).unwrap();
+    let manifest = Manifest::from_path(context.path("in")).unwrap();
+    let provider = CachingChunkProvider::new(context.path("in"));
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    syncer.sync().unwrap();
+    assert!(context.compare_hashes("in/dir/test.bin", "out/real/test.bin"));
+}
+
+#[test]
+/// Manifests larger than the limits or with inconsistent chunks are rejected.
+fn test_manifest_limits() {
+    let context = common::TestContext::new();
+
+    context.write_file("in/a.bin", 300000);
+    context.write_file("in/b.bin", 1000);
+
+    let manifest = Manifest::from_path(context.path("in")).unwrap();
+    let data = manifest.to_bytes().unwrap();
+    let compressed = manifest.to_compressed_bytes().unwrap();
+
+    let within = ManifestLimits {
+        max_bytes: data.len() as u64,
+        max_entries: 2,
+        max_total_size: 301000,
+        ..ManifestLimits::default()
+    };
+    assert_eq!(
+        manifest,
+        Manifest::from_bytes_with_limits(&compressed, &within).unwrap()
+    );
+
+    // The compressed container is smaller than its contents, which also
+    // count against the limit.
+    let limits = [
+        ManifestLimits {
+            max_bytes: compressed.len() as u64,
+            ..within
+        },
+        ManifestLimits {
+            max_entries: 1,
+            ..within
+        },
+        ManifestLimits {
+            max_total_size: 300999,
+            ..within
+        },
+    ];
+
+    for limits in &limits {
+        assert!(matches!(
+            Manifest::from_bytes_with_limits(&compressed, limits),
+            Err(BinsyncError::InvalidManifest(_))
+        ));
+    }
+
+    // Modified times can not hold more than a second of nanoseconds.
+    let mut tampered = Manifest::from_path(context.path("in")).unwrap();
+    if let Some(modified) = &mut tampered.files[0].metadata.modified {
+        modified.nanos = u32::MAX;
+    }
+    assert!(matches!(
+        Manifest::from_bytes(&tampered.to_bytes().unwrap()),
+        Err(BinsyncError::InvalidManifest(_))
+    ));
+
+    // Chunks can not be longer than the maximum chunk size in the header.
+    let mut tampered = Manifest::from_path(context.path("in")).unwrap();
+    tampered.files[0].chunks[0].length = u32::MAX as u64;
+    assert!(matches!(
+        Manifest::from_bytes(&tampered.to_bytes().unwrap()),
+        Err(BinsyncError::InvalidManifest(_))
+    ));
+
+    let provider = CachingChunkProvider::new(context.path("in"));
+    let syncer = Syncer::new(context.path("out"), provider, tampered);
+    assert!(matches!(
+        syncer.plan(),
+        Err(BinsyncError::InvalidManifest(_))
+    ));
+}
+
+#[cfg(feature = "network")]
+#[test]
+/// Packs are limited in size and can not list a chunk more than once.
+fn test_remote_pack_limits() {
+    use binsync::RemoteManifest;
+
+    let context = common::TestContext::new();
+
+    context.write_file("in/a.bin", 300000);
+    fs::copy(context.path("in/a.bin"), context.path("in/b.bin")).unwrap();
+
+    // Chunks shared between files are only packed once.
+    let remote = RemoteManifest::from_manifest(Manifest::from_path(context.path("in")).unwrap());
+    let packed: usize = remote.packs.iter().map(|p| p.chunks.len()).sum();
+    assert_eq!(packed, remote.source.stats().unique_chunks);
+
+    let data = remote.to_bytes().unwrap();
+    assert_eq!(remote, RemoteManifest::from_bytes(&data).unwrap());
+
+    let limits = [
+        ManifestLimits {
+            max_pack_size: remote.packs[0].length - 1,
+            ..ManifestLimits::default()
+        },
+        ManifestLimits {
+            max_pack_chunks: remote.packs[0].chunks.len() - 1,
+            ..ManifestLimits::default()
+        },
+    ];
+
+    for limits in &limits {
+        assert!(matches!(
+            RemoteManifest::from_bytes_with_limits(&data, limits),
+            Err(BinsyncError::InvalidManifest(_))
+        ));
+    }
+
+    // A pack can not list the same chunk twice, even with a matching length.
+    let mut tampered = RemoteManifest::from_bytes(&data).unwrap();
+    let chunk = tampered.source.files[0].chunks[0].clone();
+    tampered.packs[0].chunks.push(chunk.hash);
+    tampered.packs[0].length += chunk.length;
+    assert!(matches!(
+        RemoteManifest::from_bytes(&tampered.to_bytes().unwrap()),
+        Err(BinsyncError::InvalidManifest(_))
+    ));
+}
+
+#[cfg(feature = "network")]
+#[test]
+/// Chunks are fetched from packs over HTTP and checked against their ids.
+fn test_remote_chunk_provider() {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use binsync::{RemoteChunkProvider, RemoteManifest};
+
+    let context = common::TestContext::new();
+    context.write_file("in/a.bin", 300000);
+
+    let remote = RemoteManifest::from_manifest(Manifest::from_path(context.path("in")).unwrap());
+
+    // Packs are written the same way as the serializer example.
+    let data = fs::read(context.path("in/a.bin")).unwrap();
+    let write_packs = |remote: &RemoteManifest, data: &[u8]| {
+        for pack in &remote.packs {
+            let mut bytes = Vec::new();
+            for chunk_id in &pack.chunks {
+                let chunk = remote.source.files[0]
+                    .chunks
+                    .iter()
+                    .find(|c| c.hash == *chunk_id)
+                    .unwrap();
+                bytes.extend_from_slice(&data[chunk.offset as usize..][..chunk.length as usize]);
+            }
+
+            let path = context.path(&format!("packs/{}", remote.pack_file_name(pack)));
+            fs::create_dir_all(context.path("packs")).unwrap();
+            fs::write(path, bytes).unwrap();
+        }
+    };
+    write_packs(&remote, &data);
+
+    // Serves the pack folder over plain HTTP, one request per connection.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let packs = PathBuf::from(context.path("packs"));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..read]);
+            }
+
+            let request = String::from_utf8_lossy(&request);
+            let name = request
+                .split(' ')
+                .nth(1)
+                .unwrap_or("/")
+                .trim_start_matches('/');
+            let body = fs::read(packs.join(name)).unwrap_or_default();
+            let header = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(header.as_bytes()).unwrap();
+            stream.write_all(&body).unwrap();
+        }
+    });
+
+    let provider = RemoteChunkProvider::new(&base_url, &remote).unwrap();
+    let manifest = Manifest::from_path(context.path("in")).unwrap();
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    syncer.sync().unwrap();
+    assert!(context.compare_hashes("in/a.bin", "out/a.bin"));
+
+    // A pack with the right length but modified contents is never written.
+    let mut tampered = data.clone();
+    tampered[0] ^= 0xff;
+    write_packs(&remote, &tampered);
+
+    let provider = RemoteChunkProvider::new(&base_url, &remote).unwrap();
+    let manifest = Manifest::from_path(context.path("in")).unwrap();
+    let mut syncer = Syncer::new(context.path("tampered"), provider, manifest);
+    assert!(matches!(syncer.sync(), Err(BinsyncError::CorruptChunk(_))));
+    assert!(fs::read(context.path("tampered/a.bin"))
+        .unwrap_or_default()
+        .is_empty());
+}
+
+#[test]
+/// All-zero chunks are never fetched and are left as holes in the file.
+fn test_zero_chunks() {
+    use rand::RngCore;
+
+    let context = common::TestContext::new();
+
+    let mut data = vec![0; 4 * 1048576];
+    rand::thread_rng().fill_bytes(&mut data[..200000]);
+    rand::thread_rng().fill_bytes(&mut data[3 * 1048576..]);
+    fs::write(context.path("in/disk.img"), &data).unwrap();
+
+    let manifest = Manifest::from_path(context.path("in")).unwrap();
+    let zeros: u64 = manifest.files[0]
+        .chunks
+        .iter()
+        .filter(|c| c.zero)
+        .map(|c| c.length)
+        .sum();
+    assert!(zeros > 2 * 1048576);
+
+    // Nothing is fetched for zeros, whether the file is new or not.
+    let provider = CachingChunkProvider::new(context.path("in"));
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    let plan = syncer.plan().unwrap();
+    assert_eq!(data.len() as u64 - zeros, plan.get_fetch_size());
+    syncer.sync_from_plan(&plan).unwrap();
+    assert!(context.compare_hashes("in/disk.img", "out/disk.img"));
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = fs::metadata(context.path("out/disk.img")).unwrap();
+        assert!(metadata.blocks() * 512 < metadata.len());
+    }
+
+    // An unchanged file has nothing left to do, zeros included.
+    let manifest = Manifest::from_path(context.path("in")).unwrap();
+    let provider = CachingChunkProvider::new(context.path("in"));
+    let syncer = Syncer::new(context.path("out"), provider, manifest);
+    assert!(syncer.plan().unwrap().operations.is_empty());
+
+    // Data where the zeros belong is cleared.
+    context.write_file("out/disk.img", data.len() as u64);
+    let manifest = Manifest::from_path(context.path("in")).unwrap();
+    let provider = CachingChunkProvider::new(context.path("in"));
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    syncer.sync().unwrap();
+    assert!(context.compare_hashes("in/disk.img", "out/disk.img"));
+}
+
+#[test]
+/// Manifests generated from readers match ones generated from disk.
+fn test_reader_source() {
+    use std::io::Cursor;
+
+    let context = common::TestContext::new();
+    context.write_file("in/a.bin", 1048576);
+    context.write_file("in/sub/b.bin", 300000);
+
+    let a = fs::read(context.path("in/a.bin")).unwrap();
+    let b = fs::read(context.path("in/sub/b.bin")).unwrap();
+
+    // The last entry for a path wins.
+    let source = ReaderSource::new(vec![
+        ("sub/b.bin", Cursor::new(a.clone())),
+        ("a.bin", Cursor::new(a)),
+        ("sub/b.bin", Cursor::new(b)),
+    ]);
+    let manifest = Manifest::from_source(source).unwrap();
+    let expected = Manifest::from_path(context.path("in")).unwrap();
+
+    assert_eq!(expected.files.len(), manifest.files.len());
+    for (file, expected) in manifest.files.iter().zip(&expected.files) {
+        assert_eq!(expected.path, file.path);
+        assert_eq!(expected.digest, file.digest);
+        assert_eq!(expected.chunks, file.chunks);
+    }
+
+    let provider = CachingChunkProvider::new(context.path("in"));
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    syncer.sync().unwrap();
+    assert!(context.compare_hashes("in/sub/b.bin", "out/sub/b.bin"));
+
+    let source = ReaderSource::new([("../escape", Cursor::new(vec![1]))]);
+    assert!(matches!(
+        Manifest::from_source(source),
+        Err(BinsyncError::UnsafePath(_))
+    ));
+}
+
+#[cfg(feature = "tar")]
+#[test]
+/// Tar archives can be both the source of a manifest and its chunks.
+fn test_tar_source() {
+    use binsync::{TarChunkProvider, TarSource};
+
+    let context = common::TestContext::new();
+    context.write_file("in/a.bin", 1048576);
+    context.write_file("in/sub/b.bin", 300000);
+
+    let archive = context.path("source.tar");
+    let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+    builder.append_dir_all(".", context.path("in")).unwrap();
+
+    // Later entries replace earlier ones even when the kind changes.
+    builder
+        .append_path_with_name(context.path("in/a.bin"), "c.bin")
+        .unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    builder.append_link(&mut header, "c.bin", "a.bin").unwrap();
+    builder
+        .append_path_with_name(context.path("in/sub/b.bin"), "d")
+        .unwrap();
+    builder.append_dir("d", context.path("in/sub")).unwrap();
+    builder.into_inner().unwrap();
+
+    let source = TarSource::new(fs::File::open(&archive).unwrap());
+    let manifest = Manifest::from_source(source).unwrap();
+    assert_eq!(2, manifest.files.len());
+    assert!(manifest
+        .directories
+        .iter()
+        .any(|d| d.path == Path::new("sub")));
+    assert!(manifest
+        .directories
+        .iter()
+        .any(|d| d.path == Path::new("d")));
+    assert_eq!(1, manifest.links.len());
+    assert_eq!(Path::new("c.bin"), manifest.links[0].path);
+
+    let provider = TarChunkProvider::new(&archive).unwrap();
+    let mut syncer = Syncer::new(context.path("out"), provider, manifest);
+    syncer.sync().unwrap();
+    assert!(context.compare_hashes("in/a.bin", "out/a.bin"));
+    assert!(context.compare_hashes("in/sub/b.bin", "out/sub/b.bin"));
+    assert!(Path::new(&context.path("out/d")).is_dir());
+    assert_eq!(
+        Path::new("a.bin"),
+        fs::read_link(context.path("out/c.bin")).unwrap()
+    );
+}